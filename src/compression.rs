@@ -0,0 +1,328 @@
+//! Response compression
+//!
+//! Responses are compressed with the best encoding the client accepts, according to the q-values
+//! in its `Accept-Encoding` header. Only text-like content types are compressed, and bodies
+//! below a minimum size are sent as-is, since compression would gain little.
+//!
+//! - In-memory bodies are compressed up-front, so they keep a `Content-Length`
+//! - Files are compressed while they are streamed, using chunked transfer encoding
+//! - Static files may have precompressed `.br` or `.gz` siblings, which are served instead
+
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use flate2::{
+    Compression,
+    read::{DeflateEncoder as DeflateReader, GzEncoder as GzReader},
+    write::{DeflateEncoder, GzEncoder},
+};
+use http::{
+    HeaderMap,
+    HeaderValue,
+    StatusCode,
+    header::{ACCEPT_ENCODING, ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, VARY},
+};
+
+use crate::response::{Body, Response};
+
+/// Quality used for brotli compression on the fly, trading ratio for speed
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// Encodings the server can produce, in order of preference
+const SUPPORTED: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+/// Encodings that may be found as precompressed files, in order of preference
+const PRECOMPRESSED: [Encoding; 2] = [Encoding::Brotli, Encoding::Gzip];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+    Identity,
+}
+
+impl Encoding {
+    /// The token for this encoding in `Accept-Encoding` and `Content-Encoding`
+    pub fn token(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Identity => "identity",
+        }
+    }
+
+    /// The extension of a precompressed file with this encoding
+    fn extension(&self) -> Option<&'static str> {
+        match self {
+            Encoding::Brotli => Some("br"),
+            Encoding::Gzip => Some("gz"),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, token: &str) -> bool {
+        token.eq_ignore_ascii_case(self.token())
+            || (*self == Encoding::Gzip && token.eq_ignore_ascii_case("x-gzip"))
+    }
+}
+
+/// Choose the encoding for a response from the request's `Accept-Encoding` header
+///
+/// The encoding with the highest q-value wins, and ties go to the first in `available`.
+/// Without an `Accept-Encoding` header, no encoding is used.
+pub fn negotiate(req_headers: &HeaderMap, available: &[Encoding]) -> Encoding {
+    let accept = req_headers.get_all(ACCEPT_ENCODING).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(parse_coding)
+        .collect::<Vec<_>>();
+    let wildcard = accept.iter().find(|(token, _)| token == "*").map(|(_, q)| *q);
+    let mut best = (Encoding::Identity, 0.0);
+    for encoding in available {
+        let q = accept.iter()
+            .find(|(token, _)| encoding.matches(token))
+            .map(|(_, q)| *q)
+            .or(wildcard)
+            .unwrap_or(0.0);
+        if q > best.1 {
+            best = (*encoding, q);
+        }
+    }
+    best.0
+}
+
+/// Parse a single coding and its q-value, like `gzip;q=0.8`
+fn parse_coding(item: &str) -> Option<(String, f32)> {
+    let mut params = item.split(';');
+    let token = params.next()?.trim();
+    if token.is_empty() {
+        return None;
+    }
+    let q = params
+        .filter_map(|p| p.trim().strip_prefix("q=").or_else(|| p.trim().strip_prefix("Q=")))
+        .find_map(|q| q.trim().parse::<f32>().ok())
+        .unwrap_or(1.0);
+    Some((token.to_string(), q))
+}
+
+/// Whether compressing a content type is worthwhile
+///
+/// Images, audio, video, archives and most fonts are already compressed.
+pub fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    mime.starts_with("text/")
+        || mime.ends_with("+xml")
+        || mime.ends_with("+json")
+        || matches!(mime.as_str(),
+            "application/json" | "application/javascript" | "application/xml"
+            | "application/yaml" | "application/toml" | "application/x-tex"
+            | "application/x-bibtex" | "application/wasm" | "font/ttf" | "font/otf"
+            | "image/x-icon" | "image/bmp")
+}
+
+/// Compress a response, if the client accepts it and the content is worth compressing
+///
+/// Only complete (200) responses are compressed, since ranges refer to the uncompressed
+/// content. The ETag gets a suffix for the encoding, so that it differs between encodings.
+pub fn compress(req_headers: &HeaderMap, mut resp: Response<Body>, min_size: usize) -> Response<Body> {
+    let encoding = choose(req_headers, &mut resp, min_size);
+    tag_etag(resp.headers_mut(), encoding);
+    encode(resp, encoding)
+}
+
+/// The encoding to compress a response with, which is `Identity` if it should not be
+///
+/// This adds `Vary: Accept-Encoding` to responses that could have been compressed.
+pub fn choose(req_headers: &HeaderMap, resp: &mut Response<Body>, min_size: usize) -> Encoding {
+    if resp.status() != StatusCode::OK || resp.headers().contains_key(CONTENT_ENCODING) {
+        return Encoding::Identity;
+    }
+    let compressible = resp.headers().get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(is_compressible)
+        .unwrap_or(false);
+    // Multipart bodies are only used for ranges
+    if !compressible || matches!(resp.body(), Body::Parts(_)) {
+        return Encoding::Identity;
+    }
+    add_vary(resp.headers_mut());
+    if resp.body().len().map(|len| len < min_size as u64).unwrap_or(false) {
+        return Encoding::Identity;
+    }
+    negotiate(req_headers, &SUPPORTED)
+}
+
+/// Compress the body of a response whose ETag was already tagged with `tag_etag`
+pub fn encode(resp: Response<Body>, encoding: Encoding) -> Response<Body> {
+    if encoding == Encoding::Identity {
+        return resp;
+    }
+    let (mut parts, body) = resp.into_parts();
+    let body = match body {
+        Body::Bytes(content) => match compress_bytes(&content, encoding) {
+            Ok(compressed) => Body::Bytes(compressed),
+            Err(e) => {
+                eprintln!("{e}");
+                // The ETag is that of the compressed content
+                parts.headers.remove(ETAG);
+                return Response::from_parts(parts, Body::Bytes(content));
+            },
+        },
+        Body::File { mut file, offset, len } => {
+            if let Err(e) = file.seek(SeekFrom::Start(offset)) {
+                eprintln!("{e}");
+                parts.headers.remove(ETAG);
+                return Response::from_parts(parts, Body::File { file, offset, len });
+            }
+            compress_reader(Box::new(file.take(len)), encoding)
+        },
+        Body::Stream(reader) => compress_reader(reader, encoding),
+        body @ Body::Parts(_) => return Response::from_parts(parts, body),
+    };
+    parts.headers.remove(ACCEPT_RANGES);
+    match body.len() {
+        Some(len) => { parts.headers.insert(CONTENT_LENGTH, len.into()); },
+        None => { parts.headers.remove(CONTENT_LENGTH); },
+    }
+    parts.headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.token()));
+    Response::from_parts(parts, body)
+}
+
+/// Find a precompressed sibling of a file that the client accepts
+///
+/// For `styles.css`, this looks for `styles.css.br` and `styles.css.gz`.
+pub fn precompressed(path: &Path, req_headers: &HeaderMap) -> Option<(PathBuf, Encoding)> {
+    let available: Vec<Encoding> = PRECOMPRESSED.into_iter()
+        .filter(|e| sibling(path, *e).is_file())
+        .collect();
+    match negotiate(req_headers, &available) {
+        Encoding::Identity => None,
+        encoding => Some((sibling(path, encoding), encoding)),
+    }
+}
+
+fn sibling(path: &Path, encoding: Encoding) -> PathBuf {
+    let mut sibling = path.as_os_str().to_os_string();
+    sibling.push(".");
+    sibling.push(encoding.extension().unwrap_or_default());
+    PathBuf::from(sibling)
+}
+
+/// Set `Content-Encoding`, `Vary`, and mark the ETag as belonging to the encoded content
+pub fn set_encoding(headers: &mut HeaderMap, encoding: Encoding) {
+    headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.token()));
+    add_vary(headers);
+    tag_etag(headers, encoding);
+}
+
+/// Mark the ETag as belonging to the content in an encoding
+pub fn tag_etag(headers: &mut HeaderMap, encoding: Encoding) {
+    if encoding == Encoding::Identity {
+        return;
+    }
+    let etag = headers.get(ETAG).and_then(|v| v.to_str().ok())
+        .and_then(|etag| etag.strip_suffix('"'))
+        .map(|etag| format!("{etag}-{}\"", encoding.token()));
+    if let Some(Ok(etag)) = etag.map(|e| HeaderValue::from_str(&e)) {
+        headers.insert(ETAG, etag);
+    }
+}
+
+fn add_vary(headers: &mut HeaderMap) {
+    let present = headers.get_all(VARY).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim().eq_ignore_ascii_case(ACCEPT_ENCODING.as_str()));
+    if !present {
+        headers.append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+}
+
+fn compress_bytes(content: &[u8], encoding: Encoding) -> io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Brotli => {
+            let mut out = Vec::new();
+            {
+                let mut writer = brotli::CompressorWriter::new(&mut out, BUFFER_SIZE, BROTLI_QUALITY, BROTLI_WINDOW);
+                writer.write_all(content)?;
+            }
+            Ok(out)
+        },
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(content)?;
+            encoder.finish()
+        },
+        Encoding::Deflate => {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(content)?;
+            encoder.finish()
+        },
+        Encoding::Identity => Ok(content.to_vec()),
+    }
+}
+
+fn compress_reader(reader: Box<dyn Read + Send>, encoding: Encoding) -> Body {
+    match encoding {
+        Encoding::Brotli => Body::Stream(Box::new(
+            brotli::CompressorReader::new(reader, BUFFER_SIZE, BROTLI_QUALITY, BROTLI_WINDOW))),
+        Encoding::Gzip => Body::Stream(Box::new(GzReader::new(reader, Compression::default()))),
+        Encoding::Deflate => Body::Stream(Box::new(DeflateReader::new(reader, Compression::default()))),
+        Encoding::Identity => Body::Stream(reader),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+
+    fn accept(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn negotiates_by_q_value() {
+        assert_eq!(negotiate(&accept("gzip, deflate, br"), &SUPPORTED), Encoding::Brotli);
+        assert_eq!(negotiate(&accept("gzip;q=1.0, br;q=0.5"), &SUPPORTED), Encoding::Gzip);
+        assert_eq!(negotiate(&accept("br;q=0, *;q=0.3"), &SUPPORTED), Encoding::Gzip);
+        assert_eq!(negotiate(&accept("identity"), &SUPPORTED), Encoding::Identity);
+        assert_eq!(negotiate(&HeaderMap::new(), &SUPPORTED), Encoding::Identity);
+    }
+
+    #[test]
+    fn compresses_large_text() {
+        let content = "some markdown text ".repeat(200);
+        let resp = crate::response::html(content.clone());
+        let resp = compress(&accept("gzip"), resp, 1024);
+        assert_eq!(resp.headers().get(CONTENT_ENCODING).unwrap(), "gzip");
+        assert_eq!(resp.headers().get(VARY).unwrap(), "Accept-Encoding");
+        let compressed = match resp.into_body() {
+            Body::Bytes(b) => b,
+            body => panic!("unexpected body {body:?}"),
+        };
+        let mut decoded = String::new();
+        GzDecoder::new(&compressed[..]).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, content);
+    }
+
+    #[test]
+    fn skips_small_and_incompressible() {
+        let resp = crate::response::html(String::from("tiny"));
+        let resp = compress(&accept("gzip"), resp, 1024);
+        assert!(resp.headers().get(CONTENT_ENCODING).is_none());
+        assert_eq!(resp.headers().get(VARY).unwrap(), "Accept-Encoding");
+
+        let resp = crate::response::with_content_type(vec![0; 4096], "image/png");
+        let resp = compress(&accept("gzip"), resp, 1024);
+        assert!(resp.headers().get(CONTENT_ENCODING).is_none());
+        assert!(resp.headers().get(VARY).is_none());
+    }
+}
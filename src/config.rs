@@ -1,38 +1,317 @@
 //! Configuration Module
 //!
+//! A `Config` is put together with a `ConfigBuilder` from several sources. Each source overrides
+//! the values set before it, so sources should be applied from lowest to highest precedence:
+//!
+//! 1. defaults (`ConfigBuilder::new`)
+//! 2. config files (`source_file`), see `Config::load` for where they are found
+//! 3. environment variables (`source_env`)
+//! 4. command-line arguments (`source_args`)
+
+mod args;
+mod file;
+
+pub use args::{ArgError, USAGE};
+pub use file::{ConfigError, CONFIG_FILE_NAME, user_config_path};
 
 use std::{
     env,
-    path::PathBuf, 
+    collections::HashMap,
+    path::{Path, PathBuf},
     net::{SocketAddr, IpAddr},
+    time::Duration,
 };
 
+use crate::request::Limits;
+
 const ROOTDIR_KEY: &str = "WEB_ROOT";
 const STATICDIR_KEY: &str = "STATIC_DIR";
 const TEMPLATEDIR_KEY: &str = "TEMPLATE_DIR";
 
 const DEFAULT_ADDR: ([u8; 4], u16)  = ([0,0,0,0], 7878);
+const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_REQUESTS: usize = 100;
+const DEFAULT_COMPRESSION_MIN_SIZE: usize = 1024;
+const DEFAULT_WORKERS: usize = 4;
+const DEFAULT_MAX_PENDING: usize = 64;
+const DEFAULT_IO_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_INDEX_FILES: [&str; 2] = ["index.md", "README.md"];
 
 /// The config object to handle how pages are served
 ///
 /// # Properties
 /// - `rootdir` the root location of all the files to be served
 /// - `staticdir` the directory that holds all "static" files
+/// - `template_dir` the directory of the page templates
+/// - `static_overrides` and `template_overrides` directories searched before `staticdir` and
+///   `template_dir`, in order, so single files of a theme can be replaced (see `static_dirs`)
 /// - `header` the file name (relative to `staticdir`) of the header to prepend to all md files
 /// - `footer` the file name (relative to `staticdir`) of the footer to append to all md files
+/// - `mime_types` custom mappings from file extension to `Content-Type`
+/// - `keep_alive_timeout` how long an idle persistent connection is kept open, unless other
+///   connections are waiting for a worker
+/// - `max_requests` the number of requests served on one connection before closing it
+/// - `compression` whether responses are compressed for clients that accept it
+/// - `compression_min_size` the smallest body (in bytes) that is worth compressing
+/// - `precompressed` whether `.br` and `.gz` siblings of static files are served
+/// - `symlinks` which symlinks may be followed out of `rootdir` and `staticdir`
+/// - `open_browser` whether to open the site in a web browser on start
+/// - `workers` the number of threads handling connections
+/// - `max_pending` the number of connections that may wait for a free worker before new ones are
+///   turned away with `503 Service Unavailable`
+/// - `read_timeout` how long reading a request may stall (zero for no limit)
+/// - `write_timeout` how long writing a response may stall (zero for no limit)
+/// - `shutdown_timeout` how long requests in progress are given to finish when shutting down
+/// - `max_header_size` the largest request line and headers (in bytes) that are accepted
+/// - `max_body_size` the largest request body (in bytes) that is accepted
+/// - `unix_socket` a Unix domain socket to listen on instead of `addr`
+/// - `socket_mode` the permissions to give `unix_socket`, like `0o660`
+/// - `tls_cert` and `tls_key` PEM files to serve HTTPS with
+/// - `tls_self_signed` whether to generate a self-signed certificate if there is none yet
+/// - `redirect_port` a port on which plain HTTP requests are redirected to HTTPS
+/// - `socket_activation` whether to listen on sockets passed by systemd (`LISTEN_FDS`) when there
+///   are any, instead of `unix_socket` or `addr`
+/// - `index_files` documents rendered as the page of the directory they are in, the first that
+///   exists winning
+/// - `mounts` more directories served under their own URL prefixes, besides `rootdir` at `/`
+/// - `base_path` the path the site is served under, like `/notes` behind a reverse proxy, or
+///   empty for `/`. It is stripped from requests and prefixed onto generated links.
+/// - `markdown` the markdown extensions used when rendering documents
 #[derive(Debug, PartialEq, Eq)]
 pub struct Config {
     pub rootdir: PathBuf,
     pub staticdir: PathBuf,
     pub template_dir: PathBuf,
+    pub static_overrides: Vec<PathBuf>,
+    pub template_overrides: Vec<PathBuf>,
     pub addr: SocketAddr,
+    pub mime_types: HashMap<String, String>,
+    pub keep_alive_timeout: Duration,
+    pub max_requests: usize,
+    pub compression: bool,
+    pub compression_min_size: usize,
+    pub precompressed: bool,
+    pub symlinks: SymlinkPolicy,
+    pub open_browser: bool,
+    pub workers: usize,
+    pub max_pending: usize,
+    pub read_timeout: Duration,
+    pub write_timeout: Duration,
+    pub shutdown_timeout: Duration,
+    pub max_header_size: usize,
+    pub max_body_size: usize,
+    pub unix_socket: Option<PathBuf>,
+    pub socket_mode: Option<u32>,
+    pub socket_activation: bool,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_self_signed: bool,
+    pub redirect_port: Option<u16>,
+    pub index_files: Vec<String>,
+    pub mounts: Vec<Mount>,
+    pub base_path: String,
+    pub markdown: MarkdownOptions,
+}
+
+/// Markdown extensions to enable when rendering documents
+///
+/// Smart punctuation is off by default, since it mangles latex.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MarkdownOptions {
+    pub tables: bool,
+    pub footnotes: bool,
+    pub strikethrough: bool,
+    pub tasklists: bool,
+    pub smart_punctuation: bool,
+    pub heading_attributes: bool,
+}
+
+impl Default for MarkdownOptions {
+    fn default() -> Self {
+        MarkdownOptions {
+            tables: true,
+            footnotes: true,
+            strikethrough: true,
+            tasklists: true,
+            smart_punctuation: false,
+            heading_attributes: true,
+        }
+    }
+}
+
+impl MarkdownOptions {
+    /// Names of the extensions, as used in config files and `--markdown`
+    pub const NAMES: [&'static str; 6] = [
+        "tables", "footnotes", "strikethrough", "tasklists", "smart-punctuation", "heading-attributes",
+    ];
+
+    /// Options with every extension disabled
+    pub fn none() -> Self {
+        MarkdownOptions {
+            tables: false,
+            footnotes: false,
+            strikethrough: false,
+            tasklists: false,
+            smart_punctuation: false,
+            heading_attributes: false,
+        }
+    }
+
+    /// Enable or disable an extension by name, returning `false` for unknown names
+    pub fn set(&mut self, name: &str, enabled: bool) -> bool {
+        let option = match name {
+            "tables" => &mut self.tables,
+            "footnotes" => &mut self.footnotes,
+            "strikethrough" => &mut self.strikethrough,
+            "tasklists" => &mut self.tasklists,
+            "smart-punctuation" => &mut self.smart_punctuation,
+            "heading-attributes" => &mut self.heading_attributes,
+            _ => return false,
+        };
+        *option = enabled;
+        true
+    }
+
+    /// The options for `pulldown_cmark::Parser`
+    pub fn to_options(self) -> pulldown_cmark::Options {
+        use pulldown_cmark::Options;
+        let mut options = Options::empty();
+        options.set(Options::ENABLE_TABLES, self.tables);
+        options.set(Options::ENABLE_FOOTNOTES, self.footnotes);
+        options.set(Options::ENABLE_STRIKETHROUGH, self.strikethrough);
+        options.set(Options::ENABLE_TASKLISTS, self.tasklists);
+        options.set(Options::ENABLE_SMART_PUNCTUATION, self.smart_punctuation);
+        options.set(Options::ENABLE_HEADING_ATTRIBUTES, self.heading_attributes);
+        options
+    }
+}
+
+/// A directory served under its own URL prefix, next to the web root
+///
+/// - `name` the name shown for it in the navigation tree
+/// - `prefix` the URL path it is served under, like `/work`
+/// - `root` the directory of files to serve
+/// - `read_only` whether requests other than `GET` and `HEAD` are refused with `405`, even once
+///   the server supports changing files
+/// - `hidden` whether it is left out of the navigation tree (it is still served)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mount {
+    pub name: String,
+    pub prefix: String,
+    pub root: PathBuf,
+    pub read_only: bool,
+    pub hidden: bool,
+}
+
+impl Mount {
+    /// Mount `root` at `prefix`, named after the last segment of the prefix
+    ///
+    /// The prefix is normalized like `set_base_path`. Returns `None` if it is empty, since `/`
+    /// is always the web root.
+    pub fn new(prefix: &str, root: &Path) -> Option<Mount> {
+        let prefix = normalize_prefix(prefix);
+        let name = prefix.rsplit('/').next()?.to_string();
+        if prefix.is_empty() {
+            return None;
+        }
+        Some(Mount { name, prefix, root: root.to_path_buf(), read_only: false, hidden: false })
+    }
+}
+
+/// Normalize a URL path prefix to either nothing or `/a/b`, without a trailing slash
+fn normalize_prefix(path: &str) -> String {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    match segments.is_empty() {
+        true => String::new(),
+        false => format!("/{}", segments.join("/")),
+    }
+}
+
+/// Policy for serving files through symbolic links
+///
+/// - `Never` refuse any path that goes through a symlink
+/// - `WithinRoot` follow symlinks, as long as the target is inside the same root (default)
+/// - `Follow` follow all symlinks, even to outside the root
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    Never,
+    #[default]
+    WithinRoot,
+    Follow,
+}
+
+impl std::str::FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(SymlinkPolicy::Never),
+            "within-root" => Ok(SymlinkPolicy::WithinRoot),
+            "follow" => Ok(SymlinkPolicy::Follow),
+            _ => Err(format!("unknown symlink policy '{s}'")),
+        }
+    }
 }
 
 impl Config {
     pub fn build() -> ConfigBuilder {
         ConfigBuilder::new()
     }
-    
+
+    /// Load the config from every source, as the server binary does
+    ///
+    /// Config files are applied in order, and each is skipped if it does not exist:
+    ///
+    /// 1. the user's config file (see `user_config_path`)
+    /// 2. `smd.toml` in the web root, which is served content, so it may only set how documents
+    ///    are presented (see `ConfigBuilder::source_root_file`)
+    /// 3. the file given with `--config`, which must exist
+    ///
+    /// The web root is only known after the other sources are applied, so they are applied
+    /// once without the web root's file to find it.
+    pub fn load<I, S>(args: I) -> Result<Config, ConfigError>
+            where I: IntoIterator<Item = S>, S: Into<std::ffi::OsString> {
+        let args: Vec<std::ffi::OsString> = args.into_iter().map(Into::into).collect();
+        let user_file = user_config_path().filter(|path| path.is_file());
+        let explicit_file = ConfigBuilder::new().source_args(args.clone())?.config_file;
+        let layered = |root_file: Option<&Path>| -> Result<ConfigBuilder, ConfigError> {
+            let mut builder = ConfigBuilder::new();
+            if let Some(path) = user_file.as_deref() {
+                builder = builder.source_file(path)?;
+            }
+            if let Some(path) = root_file {
+                builder = builder.source_root_file(path)?;
+            }
+            if let Some(path) = explicit_file.as_deref() {
+                builder = builder.source_file(path)?;
+            }
+            Ok(builder.source_env().source_args(args.clone())?)
+        };
+        let builder = layered(None)?;
+        let root_file = builder.config.rootdir.join(CONFIG_FILE_NAME);
+        if !root_file.is_file() {
+            return Ok(builder.build());
+        }
+        Ok(layered(Some(&root_file))?.build())
+    }
+}
+
+impl Config {
+    /// Directories searched for static files, the first match winning
+    pub fn static_dirs(&self) -> impl Iterator<Item = &PathBuf> {
+        self.static_overrides.iter().chain(std::iter::once(&self.staticdir))
+    }
+
+    /// Directories searched for templates, the first match winning
+    pub fn template_dirs(&self) -> impl Iterator<Item = &PathBuf> {
+        self.template_overrides.iter().chain(std::iter::once(&self.template_dir))
+    }
+
+    /// Whether connections are served over TLS
+    pub fn tls_enabled(&self) -> bool {
+        self.tls_self_signed || self.tls_cert.is_some() || self.tls_key.is_some()
+    }
 }
 
 impl Default for Config {
@@ -42,6 +321,34 @@ impl Default for Config {
             rootdir: PathBuf::from("./"),
             staticdir: PathBuf::from("./sample/static"),
             template_dir: PathBuf::from("./sample/templates"),
+            static_overrides: Vec::new(),
+            template_overrides: Vec::new(),
+            mime_types: HashMap::new(),
+            keep_alive_timeout: DEFAULT_KEEP_ALIVE,
+            max_requests: DEFAULT_MAX_REQUESTS,
+            compression: true,
+            compression_min_size: DEFAULT_COMPRESSION_MIN_SIZE,
+            precompressed: true,
+            symlinks: SymlinkPolicy::default(),
+            open_browser: false,
+            workers: DEFAULT_WORKERS,
+            max_pending: DEFAULT_MAX_PENDING,
+            read_timeout: DEFAULT_IO_TIMEOUT,
+            write_timeout: DEFAULT_IO_TIMEOUT,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            max_header_size: Limits::default().header_size,
+            max_body_size: Limits::default().body_size,
+            unix_socket: None,
+            socket_mode: None,
+            socket_activation: true,
+            tls_cert: None,
+            tls_key: None,
+            tls_self_signed: false,
+            redirect_port: None,
+            index_files: DEFAULT_INDEX_FILES.iter().map(|name| name.to_string()).collect(),
+            mounts: Vec::new(),
+            base_path: String::new(),
+            markdown: MarkdownOptions::default(),
         }
     }
 }
@@ -49,35 +356,29 @@ impl Default for Config {
 
 /// Builder for the configuration object
 ///
-/// Only handles setting config values from variables.
-/// Reading from commandline arguments or environment must be done separately.
+/// Values can be set directly, or sourced from config files (`source_file`), the environment
+/// (`source_env`) and command-line arguments (`source_args`).
 pub struct ConfigBuilder {
-    rootdir: PathBuf,
-    staticdir: PathBuf,
-    template_dir: PathBuf,
-    addr: SocketAddr,
+    config: Config,
+    /// Config file given on the command line
+    config_file: Option<PathBuf>,
+}
+
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ConfigBuilder {
     /// Start building a config from the defaults
     pub fn new() -> ConfigBuilder {
-        let config = Config::default();
-        ConfigBuilder { 
-            rootdir: config.rootdir,
-            staticdir: config.staticdir,
-            template_dir: config.template_dir,
-            addr: config.addr,
-        }
+        ConfigBuilder { config: Config::default(), config_file: None }
     }
     
     /// Returns the finished Config
     pub fn build(self) -> Config {
-        Config {
-            rootdir: self.rootdir,
-            staticdir: self.staticdir,
-            template_dir: self.template_dir,
-            addr: self.addr,
-        }
+        self.config
     }
 
     /// Sources Environment variables for the config
@@ -87,48 +388,239 @@ impl ConfigBuilder {
     pub fn source_env(mut self) -> Self {
         if let Some(rootdir) = env::var_os(ROOTDIR_KEY) {
             eprintln!("rootdir found as {:?}", rootdir);
-            self.rootdir = PathBuf::from(rootdir);
+            self.config.rootdir = PathBuf::from(rootdir);
         }
         if let Some(static_dir) = env::var_os(STATICDIR_KEY) {
             eprintln!("static dir found as {:?}", static_dir);
-            self.staticdir = PathBuf::from(static_dir);
+            self.config.staticdir = PathBuf::from(static_dir);
         }
         if let Some(template_dir) = env::var_os(TEMPLATEDIR_KEY) {
             eprintln!("template dir found as {:?}", template_dir);
-            self.template_dir = PathBuf::from(template_dir);
+            self.config.template_dir = PathBuf::from(template_dir);
         }
         self
     }
 
     /// Set the root directory for the fileserver
     pub fn set_root(mut self, path: &str) -> ConfigBuilder{
-        self.rootdir = PathBuf::from(path);
+        self.config.rootdir = PathBuf::from(path);
         self
     }
 
     /// Set the static directory for the fileserver
     pub fn set_static(mut self, path: &str) -> ConfigBuilder{
-        self.staticdir = PathBuf::from(path);
+        self.config.staticdir = PathBuf::from(path);
+        self
+    }
+
+    /// Set the directory of the page templates
+    pub fn set_templates(mut self, path: &str) -> ConfigBuilder{
+        self.config.template_dir = PathBuf::from(path);
+        self
+    }
+
+    /// Search a directory for static files before the others
+    pub fn add_static_override(mut self, path: &str) -> ConfigBuilder {
+        self.config.static_overrides.insert(0, PathBuf::from(path));
+        self
+    }
+
+    /// Search a directory for templates before the others
+    pub fn add_template_override(mut self, path: &str) -> ConfigBuilder {
+        self.config.template_overrides.insert(0, PathBuf::from(path));
         self
     }
 
     pub fn set_address<T>(mut self, addr: T) -> ConfigBuilder 
         where SocketAddr: From<T> {
-            self.addr = SocketAddr::from(addr);
+            self.config.addr = SocketAddr::from(addr);
             self
         }
 
     pub fn set_ip<T>(mut self, new_ip: T) -> ConfigBuilder
         where IpAddr: From<T> {
-            self.addr.set_ip(IpAddr::from(new_ip));
+            self.config.addr.set_ip(IpAddr::from(new_ip));
             self
         }
 
     pub fn set_port(mut self, new_port: u16) -> ConfigBuilder {
-            self.addr.set_port(new_port);
+            self.config.addr.set_port(new_port);
             self
         }
 
+    /// Map a file extension to a custom `Content-Type`
+    ///
+    /// Custom mappings take precedence over the built-in table. A type that is not a valid header
+    /// value (see `mime::is_valid`) is ignored.
+    pub fn add_mime_type(mut self, ext: &str, mime: &str) -> ConfigBuilder {
+        if !crate::mime::is_valid(mime) {
+            eprintln!("Ignoring invalid MIME type '{mime}' for .{ext}");
+            return self;
+        }
+        self.config.mime_types.insert(ext.to_string(), mime.to_string());
+        self
+    }
+
+    /// Set how long an idle persistent connection is kept open
+    pub fn set_keep_alive_timeout(mut self, timeout: Duration) -> ConfigBuilder {
+        self.config.keep_alive_timeout = timeout;
+        self
+    }
+
+    /// Set the maximum number of requests served on a single connection
+    ///
+    /// A value of `1` disables persistent connections.
+    pub fn set_max_requests(mut self, max_requests: usize) -> ConfigBuilder {
+        self.config.max_requests = max_requests.max(1);
+        self
+    }
+
+    /// Enable or disable response compression
+    pub fn set_compression(mut self, enabled: bool) -> ConfigBuilder {
+        self.config.compression = enabled;
+        self
+    }
+
+    /// Set the smallest body size (in bytes) that gets compressed
+    pub fn set_compression_min_size(mut self, min_size: usize) -> ConfigBuilder {
+        self.config.compression_min_size = min_size;
+        self
+    }
+
+    /// Enable or disable serving precompressed `.br` and `.gz` static files
+    pub fn set_precompressed(mut self, enabled: bool) -> ConfigBuilder {
+        self.config.precompressed = enabled;
+        self
+    }
+
+    /// Set which symlinks may be followed when serving files
+    pub fn set_symlink_policy(mut self, policy: SymlinkPolicy) -> ConfigBuilder {
+        self.config.symlinks = policy;
+        self
+    }
+
+    /// Open the site in a web browser once the server has started
+    pub fn set_open_browser(mut self, open: bool) -> ConfigBuilder {
+        self.config.open_browser = open;
+        self
+    }
+
+    /// Set the number of threads handling connections (at least 1)
+    pub fn set_workers(mut self, workers: usize) -> ConfigBuilder {
+        self.config.workers = workers.max(1);
+        self
+    }
+
+    /// Set how many connections may wait for a free worker
+    ///
+    /// With `0`, connections are turned away whenever every worker is busy.
+    pub fn set_max_pending(mut self, max_pending: usize) -> ConfigBuilder {
+        self.config.max_pending = max_pending;
+        self
+    }
+
+    /// Set how long reading a request may stall, or `Duration::ZERO` for no limit
+    pub fn set_read_timeout(mut self, timeout: Duration) -> ConfigBuilder {
+        self.config.read_timeout = timeout;
+        self
+    }
+
+    /// Set how long writing a response may stall, or `Duration::ZERO` for no limit
+    pub fn set_write_timeout(mut self, timeout: Duration) -> ConfigBuilder {
+        self.config.write_timeout = timeout;
+        self
+    }
+
+    /// Set how long requests in progress are given to finish when shutting down
+    pub fn set_shutdown_timeout(mut self, timeout: Duration) -> ConfigBuilder {
+        self.config.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Set the largest request line and headers (in bytes) that are accepted
+    pub fn set_max_header_size(mut self, size: usize) -> ConfigBuilder {
+        self.config.max_header_size = size;
+        self
+    }
+
+    /// Set the largest request body (in bytes) that is accepted
+    pub fn set_max_body_size(mut self, size: usize) -> ConfigBuilder {
+        self.config.max_body_size = size;
+        self
+    }
+
+    /// Listen on a Unix domain socket instead of a TCP address
+    pub fn set_unix_socket(mut self, path: &str) -> ConfigBuilder {
+        self.config.unix_socket = Some(PathBuf::from(path));
+        self
+    }
+
+    /// Set the permissions of the Unix domain socket, like `0o660`
+    pub fn set_socket_mode(mut self, mode: u32) -> ConfigBuilder {
+        self.config.socket_mode = Some(mode);
+        self
+    }
+
+    /// Enable or disable listening on sockets passed by systemd
+    pub fn set_socket_activation(mut self, enabled: bool) -> ConfigBuilder {
+        self.config.socket_activation = enabled;
+        self
+    }
+
+    /// Set the PEM file holding the TLS certificate chain
+    pub fn set_tls_cert(mut self, path: &str) -> ConfigBuilder {
+        self.config.tls_cert = Some(PathBuf::from(path));
+        self
+    }
+
+    /// Set the PEM file holding the TLS private key
+    pub fn set_tls_key(mut self, path: &str) -> ConfigBuilder {
+        self.config.tls_key = Some(PathBuf::from(path));
+        self
+    }
+
+    /// Generate a self-signed certificate if there is none yet
+    pub fn set_self_signed(mut self, enabled: bool) -> ConfigBuilder {
+        self.config.tls_self_signed = enabled;
+        self
+    }
+
+    /// Redirect plain HTTP requests on this port to HTTPS
+    pub fn set_redirect_port(mut self, port: u16) -> ConfigBuilder {
+        self.config.redirect_port = Some(port);
+        self
+    }
+
+    /// Serve the site under a path prefix, like `/notes`
+    ///
+    /// Slashes are normalized, so `notes/`, `/notes` and `//notes//` are the same, and `/` is
+    /// the same as no prefix.
+    pub fn set_base_path(mut self, path: &str) -> ConfigBuilder {
+        self.config.base_path = normalize_prefix(path);
+        self
+    }
+
+    /// Set the documents used as directory pages, in order of preference
+    pub fn set_index_files<S: AsRef<str>>(mut self, names: &[S]) -> ConfigBuilder {
+        self.config.index_files = names.iter().map(|name| name.as_ref().to_string()).collect();
+        self
+    }
+
+    /// Serve another directory under its own prefix
+    ///
+    /// A later mount at the same prefix replaces an earlier one.
+    pub fn add_mount(mut self, mount: Mount) -> ConfigBuilder {
+        self.config.mounts.retain(|m| m.prefix != mount.prefix);
+        self.config.mounts.push(mount);
+        self
+    }
+
+    /// Set the markdown extensions used when rendering documents
+    pub fn set_markdown_options(mut self, options: MarkdownOptions) -> ConfigBuilder {
+        self.config.markdown = options;
+        self
+    }
+
 }
 
 
@@ -188,6 +680,56 @@ mod tests {
         assert_eq!(built.addr.port(), SocketAddr::from(addr_source).port())
     }
 
+    mod args_tests {
+        use super::super::*;
+
+        #[test]
+        fn builder_sources_args() {
+            let c = ConfigBuilder::new()
+                .source_args(["notes", "-s", "theme/static", "--port=8080", "--address", "127.0.0.1",
+                              "--mime-type", "org=text/org", "--symlinks", "never", "--no-compression",
+                              "-w", "2", "--markdown", "tables,smart-punctuation"])
+                .unwrap()
+                .build();
+            assert_eq!(PathBuf::from("notes"), c.rootdir);
+            assert_eq!(PathBuf::from("theme/static"), c.staticdir);
+            assert_eq!(c.addr, SocketAddr::from(([127,0,0,1], 8080)));
+            assert_eq!(c.mime_types.get("org"), Some(&String::from("text/org")));
+            assert_eq!(c.symlinks, SymlinkPolicy::Never);
+            assert!(!c.compression);
+            assert_eq!(c.workers, 2);
+            assert!(c.markdown.tables && c.markdown.smart_punctuation && !c.markdown.footnotes);
+        }
+
+        #[test]
+        fn args_override_earlier_sources() {
+            let c = ConfigBuilder::new()
+                .set_root("from/code")
+                .source_args(["--root", "from/args"])
+                .unwrap()
+                .build();
+            assert_eq!(PathBuf::from("from/args"), c.rootdir);
+        }
+
+        #[test]
+        fn bad_args_are_reported() {
+            let parse = |args: &[&str]| ConfigBuilder::new().source_args(args.to_vec()).err();
+            assert_eq!(parse(&["--help"]), Some(ArgError::Help));
+            assert_eq!(parse(&["-V"]), Some(ArgError::Version));
+            assert_eq!(parse(&["--port"]), Some(ArgError::MissingValue(String::from("--port"))));
+            assert_eq!(parse(&["--port", "http"]), 
+                       Some(ArgError::Invalid { flag: String::from("--port"), value: String::from("http") }));
+            assert_eq!(parse(&["--frobnicate"]), Some(ArgError::Unknown(String::from("--frobnicate"))));
+            #[cfg(unix)]
+            {
+                use std::os::unix::ffi::OsStringExt;
+                let latin1 = std::ffi::OsString::from_vec(b"notes-caf\xe9".to_vec());
+                let err = ConfigBuilder::new().source_args([std::ffi::OsString::from("--root"), latin1]).err();
+                assert_eq!(err, Some(ArgError::Invalid { flag: String::from("--root"), value: String::from("notes-caf\u{fffd}") }));
+            }
+        }
+    }
+
     mod env_tests {
         use super::super::*;
         extern crate scopeguard;
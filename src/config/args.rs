@@ -0,0 +1,241 @@
+//! Command-line arguments
+//!
+//! Arguments are parsed by hand, since there are few enough of them. Both `--flag value` and
+//! `--flag=value` are accepted for long options.
+
+use std::{
+    ffi::OsString,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use super::{ConfigBuilder, MarkdownOptions, Mount, SymlinkPolicy};
+
+/// Help text for `--help`
+pub const USAGE: &str = "\
+Usage: simple-markdown-server [OPTIONS] [ROOT]
+
+Serve a directory of markdown notes as html.
+
+Arguments:
+  [ROOT]                       Directory of files to serve (same as --root)
+
+Options:
+  -c, --config <FILE>          Config file, applied after smd.toml in the user config dir and root
+  -r, --root <DIR>             Directory of files to serve [env: WEB_ROOT]
+      --index-files <LIST>     Comma-separated documents shown as directory pages, or 'none'
+                               [default: index.md,README.md]
+  -m, --mount <PREFIX=DIR>     Also serve DIR under the URL prefix PREFIX (repeatable)
+  -s, --static <DIR>           Directory of static files (css, js) [env: STATIC_DIR]
+  -t, --templates <DIR>        Directory of tera templates [env: TEMPLATE_DIR]
+      --static-override <DIR>  Look for static files here first (repeatable, last wins)
+      --template-override <DIR>
+                               Look for templates here first (repeatable, last wins)
+  -b, --base-path <PATH>       Serve the site under a path prefix, e.g. /notes behind a proxy
+  -a, --address <IP>           Address to listen on, e.g. 127.0.0.1 for local connections only
+  -p, --port <PORT>            Port to listen on
+  -u, --unix-socket <PATH>     Listen on a Unix domain socket instead of a TCP port
+      --socket-mode <MODE>     Octal permissions of the Unix domain socket, e.g. 660
+      --no-socket-activation   Ignore sockets passed by systemd (LISTEN_FDS)
+      --tls-cert <FILE>        Serve HTTPS with this PEM certificate chain
+      --tls-key <FILE>         Private key (PEM) for --tls-cert
+      --self-signed            Serve HTTPS, generating a self-signed certificate if needed
+      --redirect-http <PORT>   Redirect plain HTTP on this port to HTTPS
+  -w, --workers <N>            Number of threads handling connections
+      --max-pending <N>        Connections waiting for a worker before others get 503
+      --read-timeout <SECS>    Time a request may stall while being read (0 for no limit)
+      --write-timeout <SECS>   Time a response may stall while being written (0 for no limit)
+      --shutdown-timeout <SECS>
+                               Time given to requests in progress when shutting down
+      --max-header-size <BYTES>
+                               Largest request headers accepted, or 431 is sent
+      --max-body-size <BYTES>  Largest request body accepted, or 413 is sent
+      --markdown <LIST>        Comma-separated markdown extensions to enable, or 'none':
+                               tables, footnotes, strikethrough, tasklists, smart-punctuation,
+                               heading-attributes
+      --mime-type <EXT=TYPE>   Serve files with extension EXT as TYPE (repeatable)
+      --keep-alive <SECS>      Idle timeout of persistent connections
+      --max-requests <N>       Requests served per connection before it is closed
+      --no-compression         Never compress responses
+      --compression-min-size <BYTES>
+                               Smallest response that is compressed
+      --no-precompressed       Ignore precompressed .gz and .br static files
+      --symlinks <POLICY>      Symlinks to follow: never, within-root, or follow
+  -o, --open                   Open the site in a web browser
+      --export-theme <DIR>     Write the built-in templates and static files to DIR, and exit
+  -h, --help                   Print this help
+  -V, --version                Print the version
+";
+
+/// Reasons that arguments did not produce a config
+///
+/// `Help`, `Version` and `ExportTheme` are not failures, but mean the program should do something
+/// else and exit.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ArgError {
+    Help,
+    Version,
+    ExportTheme(PathBuf),
+    Unknown(String),
+    MissingValue(String),
+    Invalid { flag: String, value: String },
+}
+
+impl std::error::Error for ArgError {}
+
+impl std::fmt::Display for ArgError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArgError::Help => write!(f, "{USAGE}"),
+            ArgError::Version => write!(f, "{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            ArgError::ExportTheme(dir) => write!(f, "export the theme to {}", dir.display()),
+            ArgError::Unknown(arg) => write!(f, "unknown argument '{arg}'"),
+            ArgError::MissingValue(flag) => write!(f, "'{flag}' requires a value"),
+            ArgError::Invalid { flag, value } => write!(f, "invalid value '{value}' for '{flag}'"),
+        }
+    }
+}
+
+impl ConfigBuilder {
+    /// Sources command-line arguments for the config
+    ///
+    /// `args` should not include the program name. See `USAGE` for the accepted arguments.
+    /// Arguments that are not valid unicode are `ArgError::Invalid`.
+    pub fn source_args<I, S>(mut self, args: I) -> Result<Self, ArgError>
+            where I: IntoIterator<Item = S>, S: Into<OsString> {
+        let mut args = utf8_args(args)?.into_iter();
+        while let Some(arg) = args.next() {
+            // Split `--flag=value`, so the value does not need to be a separate argument
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+                _ => (arg.clone(), None),
+            };
+            let mut value = || inline.clone().or_else(|| args.next())
+                .ok_or_else(|| ArgError::MissingValue(flag.clone()));
+            match flag.as_str() {
+                "-h" | "--help" => return Err(ArgError::Help),
+                "-V" | "--version" => return Err(ArgError::Version),
+                "--export-theme" => return Err(ArgError::ExportTheme(value()?.into())),
+                "-c" | "--config" => self.config_file = Some(value()?.into()),
+                "-r" | "--root" => self = self.set_root(&value()?),
+                "--index-files" => {
+                    let list = value()?;
+                    let names: Vec<&str> = match list.as_str() {
+                        "none" => Vec::new(),
+                        list => list.split(',').map(str::trim).collect(),
+                    };
+                    if names.iter().any(|name| name.is_empty() || name.contains(['/', '\\'])) {
+                        return Err(ArgError::Invalid { flag, value: list });
+                    }
+                    self = self.set_index_files(&names);
+                },
+                "-m" | "--mount" => {
+                    let mapping = value()?;
+                    let mount = mapping.split_once('=')
+                        .filter(|(_, dir)| !dir.is_empty())
+                        .and_then(|(prefix, dir)| Mount::new(prefix, Path::new(dir)));
+                    match mount {
+                        Some(mount) => self = self.add_mount(mount),
+                        None => return Err(ArgError::Invalid { flag, value: mapping }),
+                    }
+                },
+                "-s" | "--static" => self = self.set_static(&value()?),
+                "-t" | "--templates" => self = self.set_templates(&value()?),
+                "--static-override" => self = self.add_static_override(&value()?),
+                "--template-override" => self = self.add_template_override(&value()?),
+                "-b" | "--base-path" => self = self.set_base_path(&value()?),
+                "-a" | "--address" => {
+                    let ip: IpAddr = parse(&flag, &value()?)?;
+                    self = self.set_ip(ip);
+                },
+                "-p" | "--port" => self = self.set_port(parse(&flag, &value()?)?),
+                "-u" | "--unix-socket" => self = self.set_unix_socket(&value()?),
+                "--socket-mode" => {
+                    let mode = value()?;
+                    match u32::from_str_radix(mode.trim_start_matches("0o"), 8) {
+                        Ok(mode) if mode <= 0o7777 => self = self.set_socket_mode(mode),
+                        _ => return Err(ArgError::Invalid { flag, value: mode }),
+                    }
+                },
+                "--no-socket-activation" => self = self.set_socket_activation(false),
+                "--tls-cert" => self = self.set_tls_cert(&value()?),
+                "--tls-key" => self = self.set_tls_key(&value()?),
+                "--self-signed" => self = self.set_self_signed(true),
+                "--redirect-http" => self = self.set_redirect_port(parse(&flag, &value()?)?),
+                "-w" | "--workers" => self = self.set_workers(parse(&flag, &value()?)?),
+                "--max-pending" => self = self.set_max_pending(parse(&flag, &value()?)?),
+                "--read-timeout" => {
+                    let secs = parse(&flag, &value()?)?;
+                    self = self.set_read_timeout(Duration::from_secs(secs));
+                },
+                "--write-timeout" => {
+                    let secs = parse(&flag, &value()?)?;
+                    self = self.set_write_timeout(Duration::from_secs(secs));
+                },
+                "--shutdown-timeout" => {
+                    let secs = parse(&flag, &value()?)?;
+                    self = self.set_shutdown_timeout(Duration::from_secs(secs));
+                },
+                "--max-header-size" => self = self.set_max_header_size(parse(&flag, &value()?)?),
+                "--max-body-size" => self = self.set_max_body_size(parse(&flag, &value()?)?),
+                "--markdown" => {
+                    let list = value()?;
+                    let mut options = MarkdownOptions::none();
+                    let valid = list == "none" || list.split(',')
+                        .all(|name| options.set(name.trim(), true));
+                    if !valid {
+                        return Err(ArgError::Invalid { flag, value: list });
+                    }
+                    self = self.set_markdown_options(options);
+                },
+                "--mime-type" => {
+                    let mapping = value()?;
+                    match mapping.split_once('=') {
+                        Some((ext, mime)) if !ext.is_empty() && crate::mime::is_valid(mime) =>
+                            self = self.add_mime_type(ext, mime),
+                        _ => return Err(ArgError::Invalid { flag, value: mapping }),
+                    }
+                },
+                "--keep-alive" => {
+                    let secs = parse(&flag, &value()?)?;
+                    self = self.set_keep_alive_timeout(Duration::from_secs(secs));
+                },
+                "--max-requests" => self = self.set_max_requests(parse(&flag, &value()?)?),
+                "--no-compression" => self = self.set_compression(false),
+                "--compression-min-size" => self = self.set_compression_min_size(parse(&flag, &value()?)?),
+                "--no-precompressed" => self = self.set_precompressed(false),
+                "--symlinks" => self = self.set_symlink_policy(parse::<SymlinkPolicy>(&flag, &value()?)?),
+                "-o" | "--open" => self = self.set_open_browser(true),
+                _ if flag.starts_with('-') && flag != "-" => return Err(ArgError::Unknown(arg)),
+                // A lone positional argument is the root directory
+                _ => self = self.set_root(&arg),
+            }
+        }
+        Ok(self)
+    }
+}
+
+/// The arguments as strings, or an error for the first one that is not valid unicode
+fn utf8_args<I, S>(args: I) -> Result<Vec<String>, ArgError>
+        where I: IntoIterator<Item = S>, S: Into<OsString> {
+    let mut strings: Vec<String> = Vec::new();
+    for arg in args {
+        match arg.into().into_string() {
+            Ok(arg) => strings.push(arg),
+            Err(arg) => {
+                // The value of the previous flag, or the root
+                let flag = strings.last()
+                    .filter(|prev| prev.starts_with('-') && !prev.contains('='))
+                    .cloned()
+                    .unwrap_or_else(|| String::from("ROOT"));
+                return Err(ArgError::Invalid { flag, value: arg.to_string_lossy().into_owned() });
+            },
+        }
+    }
+    Ok(strings)
+}
+
+fn parse<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, ArgError> {
+    value.parse().map_err(|_| ArgError::Invalid { flag: flag.to_string(), value: value.to_string() })
+}
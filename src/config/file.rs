@@ -0,0 +1,432 @@
+//! Configuration files
+//!
+//! Config files are TOML, with keys named like the long command-line options:
+//!
+//! ```toml
+//! root = "notes"
+//! static = "theme/static"
+//! templates = "theme/templates"
+//! template-overrides = ["my-templates"]
+//! address = "127.0.0.1"
+//! port = 8080
+//! workers = 8
+//!
+//! [mime-types]
+//! org = "text/org"
+//!
+//! [markdown]
+//! smart-punctuation = false
+//!
+//! [[mounts]]
+//! prefix = "/shared"
+//! root = "/srv/shared-vault"
+//! read-only = true
+//! ```
+//!
+//! Relative directories are relative to the file they appear in. Unknown keys and values of the
+//! wrong type are errors, so a typo is never silently replaced by a default.
+
+use std::{
+    collections::HashMap,
+    env,
+    fs,
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use super::{ArgError, ConfigBuilder, MarkdownOptions, Mount, SymlinkPolicy};
+
+/// Name of the config file looked for in the user's config directory and the web root
+pub const CONFIG_FILE_NAME: &str = "smd.toml";
+
+/// Directory under `$XDG_CONFIG_HOME` that holds the user's config file
+const CONFIG_DIR_NAME: &str = "simple-markdown-server";
+
+/// Reasons that a config could not be loaded
+#[derive(Debug)]
+pub enum ConfigError {
+    /// A config file could not be read
+    IO(PathBuf, io::Error),
+    /// A config file is not valid TOML, or has unknown keys or values of the wrong type
+    Parse(PathBuf, toml::de::Error),
+    /// A config file has a value of the right type, but it does not make sense
+    Invalid(PathBuf, String),
+    Args(ArgError),
+}
+
+impl std::error::Error for ConfigError {}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::IO(path, e) => write!(f, "could not read {}: {e}", path.display()),
+            ConfigError::Parse(path, e) => write!(f, "in {}: {e}", path.display()),
+            ConfigError::Invalid(path, msg) => write!(f, "in {}: {msg}", path.display()),
+            ConfigError::Args(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<ArgError> for ConfigError {
+    fn from(e: ArgError) -> Self {
+        ConfigError::Args(e)
+    }
+}
+
+/// The contents of a config file
+///
+/// Every key is optional, and only keys that are present override earlier sources.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct ConfigFile {
+    root: Option<PathBuf>,
+    #[serde(rename = "static")]
+    staticdir: Option<PathBuf>,
+    templates: Option<PathBuf>,
+    /// Searched in order, before `static`
+    static_overrides: Vec<PathBuf>,
+    /// Searched in order, before `templates`
+    template_overrides: Vec<PathBuf>,
+    base_path: Option<String>,
+    index_files: Option<Vec<String>>,
+    address: Option<IpAddr>,
+    port: Option<u16>,
+    workers: Option<usize>,
+    max_pending: Option<usize>,
+    /// Seconds
+    read_timeout: Option<u64>,
+    /// Seconds
+    write_timeout: Option<u64>,
+    /// Seconds
+    shutdown_timeout: Option<u64>,
+    max_header_size: Option<usize>,
+    max_body_size: Option<usize>,
+    unix_socket: Option<PathBuf>,
+    /// Written as an octal integer, like `0o660`
+    socket_mode: Option<u32>,
+    socket_activation: Option<bool>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    self_signed: Option<bool>,
+    redirect_http: Option<u16>,
+    mime_types: HashMap<String, String>,
+    /// Seconds
+    keep_alive: Option<u64>,
+    max_requests: Option<usize>,
+    compression: Option<bool>,
+    compression_min_size: Option<usize>,
+    precompressed: Option<bool>,
+    symlinks: Option<String>,
+    open: Option<bool>,
+    markdown: Option<MarkdownFile>,
+    mounts: Vec<MountFile>,
+}
+
+/// The contents of `smd.toml` in the web root
+///
+/// The file is served content, so anyone who can write to the vault can write it. It may only
+/// change how documents are presented, and not what is served or how.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct RootFile {
+    index_files: Option<Vec<String>>,
+    markdown: Option<MarkdownFile>,
+}
+
+/// An entry of the `[[mounts]]` array
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct MountFile {
+    prefix: String,
+    root: PathBuf,
+    name: Option<String>,
+    #[serde(default)]
+    read_only: bool,
+    #[serde(default)]
+    hidden: bool,
+}
+
+/// The `[markdown]` table, which overrides individual extensions
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct MarkdownFile {
+    tables: Option<bool>,
+    footnotes: Option<bool>,
+    strikethrough: Option<bool>,
+    tasklists: Option<bool>,
+    smart_punctuation: Option<bool>,
+    heading_attributes: Option<bool>,
+}
+
+impl MarkdownFile {
+    fn apply(self, options: &mut MarkdownOptions) {
+        let fields = [
+            (self.tables, &mut options.tables),
+            (self.footnotes, &mut options.footnotes),
+            (self.strikethrough, &mut options.strikethrough),
+            (self.tasklists, &mut options.tasklists),
+            (self.smart_punctuation, &mut options.smart_punctuation),
+            (self.heading_attributes, &mut options.heading_attributes),
+        ];
+        for (value, option) in fields {
+            if let Some(value) = value {
+                *option = value;
+            }
+        }
+    }
+}
+
+impl ConfigBuilder {
+    /// Sources a config file
+    ///
+    /// The file must exist. Relative directories in the file are resolved against the
+    /// directory containing it.
+    pub fn source_file<P: AsRef<Path>>(self, path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .map_err(|e| ConfigError::IO(path.to_path_buf(), e))?;
+        let base = path.parent().unwrap_or(Path::new(""));
+        self.source_toml(&contents, base).map_err(|e| in_file(path, e))
+    }
+
+    /// Sources the `smd.toml` of the web root, which may only set `index-files` and `[markdown]`
+    pub fn source_root_file<P: AsRef<Path>>(self, path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .map_err(|e| ConfigError::IO(path.to_path_buf(), e))?;
+        self.source_root_toml(&contents).map_err(|e| in_file(path, e))
+    }
+
+    fn source_root_toml(mut self, contents: &str) -> Result<Self, ConfigError> {
+        let file: RootFile = toml::from_str(contents)
+            .map_err(|e| ConfigError::Parse(PathBuf::new(), e))?;
+        if let Some(names) = file.index_files {
+            self = self.set_index_file_names(names)?;
+        }
+        if let Some(markdown) = file.markdown {
+            markdown.apply(&mut self.config.markdown);
+        }
+        Ok(self)
+    }
+
+    fn set_index_file_names(self, names: Vec<String>) -> Result<Self, ConfigError> {
+        if names.iter().any(|name| name.is_empty() || name.contains(['/', '\\'])) {
+            return Err(ConfigError::Invalid(PathBuf::new(), String::from("index-files must be file names")));
+        }
+        Ok(self.set_index_files(&names))
+    }
+
+    /// Sources config from TOML text, with relative directories resolved against `base`
+    fn source_toml(mut self, contents: &str, base: &Path) -> Result<Self, ConfigError> {
+        let file: ConfigFile = toml::from_str(contents)
+            .map_err(|e| ConfigError::Parse(PathBuf::new(), e))?;
+        if let Some(root) = file.root {
+            self.config.rootdir = base.join(root);
+        }
+        if let Some(staticdir) = file.staticdir {
+            self.config.staticdir = base.join(staticdir);
+        }
+        if let Some(templates) = file.templates {
+            self.config.template_dir = base.join(templates);
+        }
+        // Each override is searched before the ones added earlier
+        for path in file.static_overrides.into_iter().rev() {
+            self.config.static_overrides.insert(0, base.join(path));
+        }
+        for path in file.template_overrides.into_iter().rev() {
+            self.config.template_overrides.insert(0, base.join(path));
+        }
+        if let Some(names) = file.index_files {
+            self = self.set_index_file_names(names)?;
+        }
+        if let Some(path) = file.base_path {
+            self = self.set_base_path(&path);
+        }
+        if let Some(ip) = file.address {
+            self = self.set_ip(ip);
+        }
+        if let Some(port) = file.port {
+            self = self.set_port(port);
+        }
+        if let Some(workers) = file.workers {
+            self = self.set_workers(workers);
+        }
+        if let Some(max_pending) = file.max_pending {
+            self = self.set_max_pending(max_pending);
+        }
+        if let Some(secs) = file.read_timeout {
+            self = self.set_read_timeout(Duration::from_secs(secs));
+        }
+        if let Some(secs) = file.write_timeout {
+            self = self.set_write_timeout(Duration::from_secs(secs));
+        }
+        if let Some(secs) = file.shutdown_timeout {
+            self = self.set_shutdown_timeout(Duration::from_secs(secs));
+        }
+        if let Some(size) = file.max_header_size {
+            self = self.set_max_header_size(size);
+        }
+        if let Some(size) = file.max_body_size {
+            self = self.set_max_body_size(size);
+        }
+        if let Some(path) = file.unix_socket {
+            self.config.unix_socket = Some(base.join(path));
+        }
+        if let Some(mode) = file.socket_mode {
+            if mode > 0o7777 {
+                return Err(ConfigError::Invalid(PathBuf::new(), format!("invalid socket-mode {mode:o}")));
+            }
+            self = self.set_socket_mode(mode);
+        }
+        if let Some(enabled) = file.socket_activation {
+            self = self.set_socket_activation(enabled);
+        }
+        if let Some(path) = file.tls_cert {
+            self.config.tls_cert = Some(base.join(path));
+        }
+        if let Some(path) = file.tls_key {
+            self.config.tls_key = Some(base.join(path));
+        }
+        if let Some(enabled) = file.self_signed {
+            self = self.set_self_signed(enabled);
+        }
+        if let Some(port) = file.redirect_http {
+            self = self.set_redirect_port(port);
+        }
+        for (ext, mime) in file.mime_types {
+            if !crate::mime::is_valid(&mime) {
+                return Err(ConfigError::Invalid(PathBuf::new(), format!("invalid MIME type '{mime}' for .{ext}")));
+            }
+            self = self.add_mime_type(&ext, &mime);
+        }
+        if let Some(secs) = file.keep_alive {
+            self = self.set_keep_alive_timeout(Duration::from_secs(secs));
+        }
+        if let Some(max_requests) = file.max_requests {
+            self = self.set_max_requests(max_requests);
+        }
+        if let Some(enabled) = file.compression {
+            self = self.set_compression(enabled);
+        }
+        if let Some(min_size) = file.compression_min_size {
+            self = self.set_compression_min_size(min_size);
+        }
+        if let Some(enabled) = file.precompressed {
+            self = self.set_precompressed(enabled);
+        }
+        if let Some(policy) = file.symlinks {
+            let policy: SymlinkPolicy = policy.parse()
+                .map_err(|msg| ConfigError::Invalid(PathBuf::new(), msg))?;
+            self = self.set_symlink_policy(policy);
+        }
+        if let Some(open) = file.open {
+            self = self.set_open_browser(open);
+        }
+        if let Some(markdown) = file.markdown {
+            markdown.apply(&mut self.config.markdown);
+        }
+        for entry in file.mounts {
+            let mut mount = Mount::new(&entry.prefix, &base.join(entry.root))
+                .ok_or_else(|| ConfigError::Invalid(PathBuf::new(), format!("invalid mount prefix '{}'", entry.prefix)))?;
+            if let Some(name) = entry.name {
+                mount.name = name;
+            }
+            mount.read_only = entry.read_only;
+            mount.hidden = entry.hidden;
+            self = self.add_mount(mount);
+        }
+        Ok(self)
+    }
+}
+
+/// Attach the path of a config file to an error from its contents
+fn in_file(path: &Path, e: ConfigError) -> ConfigError {
+    match e {
+        ConfigError::Parse(_, e) => ConfigError::Parse(path.to_path_buf(), e),
+        ConfigError::Invalid(_, msg) => ConfigError::Invalid(path.to_path_buf(), msg),
+        e => e,
+    }
+}
+
+/// Location of the user's config file, `$XDG_CONFIG_HOME/simple-markdown-server/smd.toml`
+///
+/// Falls back to `~/.config` when `XDG_CONFIG_HOME` is not set.
+pub fn user_config_path() -> Option<PathBuf> {
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_home.join(CONFIG_DIR_NAME).join(CONFIG_FILE_NAME))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_file_only_sets_presentation() {
+        let c = ConfigBuilder::new()
+            .source_root_toml("index-files = [\"home.md\"]\n[markdown]\ntables = false").unwrap()
+            .build();
+        assert_eq!(c.index_files, ["home.md"]);
+        assert!(!c.markdown.tables);
+        for contents in ["symlinks = \"follow\"", "static-overrides = [\"/\"]", "[[mounts]]\nprefix = \"/all\"\nroot = \"/\""] {
+            assert!(matches!(ConfigBuilder::new().source_root_toml(contents), Err(ConfigError::Parse(..))));
+        }
+    }
+
+    fn source(contents: &str) -> Result<ConfigBuilder, ConfigError> {
+        ConfigBuilder::new().source_toml(contents, Path::new("vault"))
+    }
+
+    #[test]
+    fn sources_toml() {
+        let c = source(r#"
+            root = "notes"
+            static = "/srv/theme"
+            static-overrides = ["mine", "/srv/team"]
+            port = 8080
+            workers = 8
+            symlinks = "never"
+
+            [mime-types]
+            org = "text/org"
+
+            [markdown]
+            smart-punctuation = true
+            tables = false
+
+            [[mounts]]
+            prefix = "shared/"
+            root = "/srv/shared"
+            hidden = true
+        "#).unwrap().build();
+        assert_eq!(c.rootdir, PathBuf::from("vault/notes"));
+        assert_eq!(c.staticdir, PathBuf::from("/srv/theme"));
+        let static_dirs: Vec<&PathBuf> = c.static_dirs().collect();
+        assert_eq!(static_dirs, [Path::new("vault/mine"), Path::new("/srv/team"), Path::new("/srv/theme")]);
+        assert_eq!(c.addr.port(), 8080);
+        assert_eq!(c.workers, 8);
+        assert_eq!(c.symlinks, SymlinkPolicy::Never);
+        assert_eq!(c.mime_types.get("org"), Some(&String::from("text/org")));
+        assert!(c.markdown.smart_punctuation && !c.markdown.tables && c.markdown.footnotes);
+        let shared = &c.mounts[0];
+        assert_eq!((shared.name.as_str(), shared.prefix.as_str()), ("shared", "/shared"));
+        assert!(shared.hidden && !shared.read_only);
+    }
+
+    #[test]
+    fn rejects_mistakes() {
+        assert!(matches!(source("prot = 8080"), Err(ConfigError::Parse(..))));
+        assert!(matches!(source("port = \"8080\""), Err(ConfigError::Parse(..))));
+        assert!(matches!(source("[markdown]\nmath = true"), Err(ConfigError::Parse(..))));
+        assert!(matches!(source("address = \"localhost\""), Err(ConfigError::Parse(..))));
+        assert!(matches!(source("symlinks = \"sometimes\""), Err(ConfigError::Invalid(..))));
+        assert!(matches!(source("[mime-types]\norg = \"text/org\\n\""), Err(ConfigError::Invalid(..))));
+        assert!(matches!(source("[[mounts]]\nprefix = \"/\"\nroot = \"x\""), Err(ConfigError::Invalid(..))));
+    }
+}
@@ -1,17 +1,21 @@
 //! Handlers for incoming requests
 
 use http::StatusCode;
-use pulldown_cmark::{Parser, Options, html};
 
 use std::{
     io::{BufReader, Read}, 
-    path::Path, 
-    fs::File, sync::RwLock,
+    path::{Path, PathBuf}, 
+    fs::{self, File}, sync::{Arc, RwLock},
+    time::SystemTime,
 };
 
 use crate::{
-    response::{self, Response},
+    compression,
+    response::{self, Body, Response},
     config::Config,
+    mime::{self, MimeTypes},
+    theme,
+    links::{self, LinkIndex},
     uri::{Resolved, Resolver},
 };
 
@@ -21,65 +25,158 @@ use tera::Tera;
 
 const MARKDOWN_TEMPLATE: &str = "markdown.html";
 
-mod directory;
+pub mod conditional;
+pub mod directory;
+pub mod frontmatter;
+pub mod markdown;
+pub mod range;
 pub mod walkdir;
 
 pub struct Handler {
     config: Config,
-    resolver: Resolver,
+    resolver: Arc<Resolver>,
+    links: Arc<LinkIndex>,
+    mime_types: MimeTypes,
     tera: RwLock<Tera>,
 }
 
 impl Handler {
     pub fn new(config: Config) -> Handler {
-        let resolver = Resolver::new(&config);
-        let template_glob = config.template_dir.join("**/*.html");
-        let tera = match Tera::new(&template_glob.to_str().unwrap()) {
+        let resolver = Arc::new(Resolver::new(&config));
+        let links = Arc::new(LinkIndex::new(resolver.clone(), config.markdown.to_options()));
+        let mime_types = MimeTypes::new(&config);
+        let tera = match theme::load_templates(config.template_dirs()) {
             Ok(t) => RwLock::new(t),
-            Err(e) => {eprintln!("{e}"); panic!()},
+            Err(e) => {
+                eprintln!("Could not load templates, using the built-in ones: {}", template_error(&e));
+                RwLock::new(theme::builtin_templates())
+            },
         };
-        Handler {config, resolver, tera}
+        Handler {config, resolver, links, mime_types, tera}
     }
 
-    pub fn handle_request<T>(&self, req: http::Request<T>) -> Result<Response<Vec<u8>>, std::io::Error> {
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Index the links between notes for backlinks, and keep the index and the names of notes
+    /// (for smart links) up to date as the vault changes
+    ///
+    /// Without this, there are no backlinks, and smart links only know the notes found at startup.
+    pub fn watch_links(&self) -> std::io::Result<()> {
+        links::watch(&self.links)
+    }
+
+    pub fn handle_request<T>(&self, req: http::Request<T>) -> Result<Response<Body>, std::io::Error> {
         #[cfg(debug_assertions)]
         {
             let mut lock = self.tera.write().unwrap();
-            match lock.full_reload() {
-                Ok(_) => (),
-                Err(e) => {eprintln!("{e}"); drop(lock); panic!()},
+            // Keep the previous templates until the broken ones are fixed
+            match theme::load_templates(self.config.template_dirs()) {
+                Ok(tera) => *lock = tera,
+                Err(e) => eprintln!("Could not reload templates: {}", template_error(&e)),
             }
         }
         use http::Method;
-        match req.method() {
-            &Method::GET => self.handle_get(req),
-            &Method::HEAD => self.handle_head(req),
+        match *req.method() {
+            Method::GET => self.handle_get(req),
+            Method::HEAD => self.handle_head(req),
+            _ if self.resolver.is_read_only(req.uri()) => {
+                let mut resp = response::not_allowed();
+                resp.headers_mut().insert(http::header::ALLOW, http::HeaderValue::from_static("GET, HEAD"));
+                Ok(resp)
+            },
             _ => Ok(response::unimplemented()),
         }
     }
 
-    pub fn handle_get<T>(&self, req: http::Request<T>) -> Result<Response<Vec<u8>>, std::io::Error> {
+    pub fn handle_get<T>(&self, req: http::Request<T>) -> Result<Response<Body>, std::io::Error> {
         let resource = self.resolver.lookup(req.uri());
-        let accepts = preferred_format(&req.headers());
+        let accepts = preferred_format(req.headers());
+        let json = prefers_json(req.headers());
         eprintln!("Resource Found: {:?}", resource);
         let tera = self.tera.read().unwrap();
-        match resource {
-            Resolved::File(path) => file_response(&path),
-            Resolved::Markdown(path) => markdown_response(&path, accepts, &self.config, &tera),
-            Resolved::Directory(path) => 
-                Ok(dir_response(&path, accepts, &self.config, &tera)),
-            Resolved::None => Ok(not_found_response(req.uri().path(), &self.config, &tera)),
+        let resp = match resource {
+            Resolved::File(path) => match self.precompressed(&path, req.headers()) {
+                Some((sibling, encoding)) => 
+                    precompressed_response(&path, &sibling, encoding, &self.mime_types)?,
+                None => file_response(&path, req.headers(), &self.mime_types)?,
+            },
+            Resolved::Markdown(path) => markdown_response(&path, accepts, json, None, &self.resolver, &self.links, &self.config, &tera)?,
+            Resolved::Index { dir, document } => {
+                if json {
+                    dir_response(&dir, vec![AcceptFormat::Json], &self.links, &self.config, &tera)
+                } else if wants_listing(req.uri()) {
+                    dir_response(&dir, accepts, &self.links, &self.config, &tera)
+                } else {
+                    // Absolute links, from the normalized path of the directory
+                    let base = self.resolver.link_to(&dir).unwrap_or_default();
+                    let listing = walkdir::walk_dir(&dir, Some(base.trim_end_matches('/'))).ok();
+                    markdown_response(&document, accepts, json, listing, &self.resolver, &self.links, &self.config, &tera)?
+                }
+            },
+            Resolved::Directory(path) => dir_response(&path, accepts, &self.links, &self.config, &tera),
+            Resolved::Builtin(name) => builtin_response(name, &self.mime_types),
+            Resolved::Closest(links) => match links.as_slice() {
+                [link] => match req.uri().query() {
+                    Some(query) => response::found(&format!("{link}?{query}")),
+                    None => response::found(link),
+                },
+                links => disambiguation_response(req.uri().path(), links, &self.config, &tera),
+            },
+            Resolved::Redirect(mut location) => {
+                if let Some(query) = req.uri().query() {
+                    location = format!("{location}?{query}");
+                }
+                response::moved_permanently(&location)
+            },
+            Resolved::Forbidden => response::forbidden(),
+            Resolved::None => not_found_response(req.uri().path(), &self.config, &tera),
+        };
+        let mut resp = resp;
+        conditional::set_content_etag(&mut resp);
+        let encoding = match self.config.compression {
+            true => compression::choose(req.headers(), &mut resp, self.config.compression_min_size),
+            false => compression::Encoding::Identity,
+        };
+        // Preconditions are evaluated against the validators of the encoded response, but before
+        // the work of encoding it
+        compression::tag_etag(resp.headers_mut(), encoding);
+        let resp = conditional::evaluate(req.headers(), resp);
+        if resp.status() == StatusCode::NOT_MODIFIED {
+            return Ok(resp);
+        }
+        Ok(compression::encode(resp, encoding))
+    }
+
+    /// Find a precompressed version of a static file, unless only part of it is requested
+    fn precompressed(&self, path: &Path, headers: &http::HeaderMap) -> Option<(PathBuf, compression::Encoding)> {
+        if !self.config.precompressed 
+                || !self.config.static_dirs().any(|dir| path.starts_with(dir))
+                || headers.contains_key(http::header::RANGE) {
+            return None;
         }
+        compression::precompressed(path, headers)
     }
 
-    pub fn handle_head<T>(&self, req: http::Request<T>) -> Result<Response<Vec<u8>>, std::io::Error> {
+    #[allow(clippy::needless_return)]
+    pub fn handle_head<T>(&self, req: http::Request<T>) -> Result<Response<Body>, std::io::Error> {
         let mut resp = self.handle_get(req)?;
-        *resp.body_mut() = Vec::new();
+        // Keep the headers describing the body that would have been sent
+        response::set_framing(&mut resp);
+        *resp.body_mut() = Body::empty();
         return Ok(resp);
     }
 
 }
 
+/// Whether the query asks for the listing of a directory instead of its index document
+fn wants_listing(uri: &http::Uri) -> bool {
+    uri.query().is_some_and(|query| query.split('&').any(|param| {
+        param == "listing" || param.starts_with("listing=")
+    }))
+}
+
 enum AcceptFormat {
     Html,
     PartialHtml,
@@ -92,9 +189,9 @@ fn preferred_format(headers: &http::HeaderMap) -> Vec<AcceptFormat> {
         eprintln!("Partial header: {value:?}");
         return vec![AcceptFormat::PartialHtml];
     }
-    if let Some(value) = headers.get("accept") {
-        value.to_str().expect("accept header could not be converted to string?")
-            .split(',').filter_map(|e| {
+    // A header that is not visible ascii is treated like a missing one
+    if let Some(value) = headers.get("accept").and_then(|v| v.to_str().ok()) {
+        value.split(',').filter_map(|e| {
                 if e.contains("json") {
                     Some(AcceptFormat::Json)
                 } else if e.contains("html") {
@@ -110,21 +207,60 @@ fn preferred_format(headers: &http::HeaderMap) -> Vec<AcceptFormat> {
     }
 }
 
+/// Whether a client wants json more than html, by the q-values in its `Accept` header
+///
+/// Notes are pages first, so a header like `application/json, */*` still gets html.
+fn prefers_json(headers: &http::HeaderMap) -> bool {
+    if headers.contains_key("x-partial") {
+        return false;
+    }
+    match headers.get("accept").and_then(|v| v.to_str().ok()) {
+        Some(accept) => quality(accept, "application/json") > quality(accept, "text/html"),
+        None => false,
+    }
+}
+
+/// The q-value an `Accept` header gives a media type, from the most specific range matching it
+fn quality(accept: &str, media_type: &str) -> f32 {
+    let any_subtype = media_type.split_once('/').map(|(kind, _)| format!("{kind}/*"));
+    let mut best: Option<(u8, f32)> = None;
+    for entry in accept.split(',') {
+        let mut params = entry.split(';');
+        let range = params.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let specificity = if range == media_type {
+            2
+        } else if Some(&range) == any_subtype.as_ref() {
+            1
+        } else if range == "*/*" {
+            0
+        } else {
+            continue;
+        };
+        let q = params
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .map_or(1.0, |q| q.trim().parse().unwrap_or(0.0));
+        if best.is_none_or(|(most, _)| specificity > most) {
+            best = Some((specificity, q));
+        }
+    }
+    best.map_or(0.0, |(_, q)| q)
+}
+
 // Actual responses to a get request {{{
 
 /// Respond to a missing file
-fn not_found_response(path: &str, config: &Config, tera: &Tera) -> Response<Vec<u8>> {
-    let root_contents = walkdir::walk_dir(&config.rootdir , true)
-        .expect("Problem stripping prefix?");
+fn not_found_response(path: &str, config: &Config, tera: &Tera) -> Response<Body> {
+    let root_contents = nav_tree(config);
     // Apply the template
     use tera::Context;
     let mut context = Context::new();
     let html_out = format!("File not found: {}", path);
     context.insert("content", &html_out);
     context.insert("dirtree", &root_contents);
+    context.insert("base_path", &config.base_path);
     match tera.render(MARKDOWN_TEMPLATE, &context) {
         Ok(html_out) => {
-            let mut resp = response::from_string(html_out);
+            let mut resp = response::html(html_out);
             *resp.status_mut() = StatusCode::NOT_FOUND;
             resp
         },
@@ -135,87 +271,214 @@ fn not_found_response(path: &str, config: &Config, tera: &Tera) -> Response<Vec<
     }
 }
 
+/// Respond with links to the several notes a smart link could mean
+fn disambiguation_response(path: &str, links: &[String], config: &Config, tera: &Tera) -> Response<Body> {
+    let mut html_out = format!("<p>Several notes match <code>{}</code>:</p>\n<ul>\n", tera::escape_html(path));
+    for link in links {
+        let link = tera::escape_html(link);
+        html_out.push_str(&format!("<li><a href=\"{link}\">{link}</a></li>\n"));
+    }
+    html_out.push_str("</ul>\n");
+    let mut context = tera::Context::new();
+    context.insert("content", &html_out);
+    context.insert("dirtree", &nav_tree(config));
+    context.insert("base_path", &config.base_path);
+    match tera.render(MARKDOWN_TEMPLATE, &context) {
+        Ok(html_out) => {
+            let mut resp = response::html(html_out);
+            *resp.status_mut() = StatusCode::MULTIPLE_CHOICES;
+            resp
+        },
+        Err(e) => {
+            eprintln!("{e}");
+            response::server_error()
+        }
+    }
+}
+
 /// Respond with the contents of a file
-fn file_response(path: &Path) -> Result<Response<Vec<u8>>, std::io::Error> {
-    let file = BufReader::new(File::open(path)?);
-    let contents: Result<Vec<_>, _> = file.bytes().collect();
-    Ok(response::from_bytes(contents?))
+///
+/// The file is streamed rather than read into memory, and only its first few bytes are read
+/// up-front to sniff the content type if the extension is unknown. If the request has a `Range`
+/// header (and a matching `If-Range`), only those parts of the file are sent.
+fn file_response(path: &Path, headers: &http::HeaderMap, mime_types: &MimeTypes) -> Result<Response<Body>, std::io::Error> {
+    let mut file = File::open(path)?;
+    let meta = file.metadata()?;
+    let len = meta.len();
+    let etag = conditional::file_etag(&meta);
+    let modified = meta.modified().ok();
+    let content_type = content_type(path, &mut file, mime_types)?;
+    let mut resp = match headers.get(http::header::RANGE).map(|v| v.to_str()) {
+        Some(Ok(range_header)) if conditional::if_range_matches(headers, &etag, modified) => {
+            match range::parse(range_header, len) {
+                Ok(ranges) => range::partial_response(file, len, ranges, content_type)?,
+                Err(range::RangeError::Unsatisfiable) => range::not_satisfiable(len),
+                // An invalid range header is ignored
+                Err(range::RangeError::Invalid) => response::from_file(file, len, content_type),
+            }
+        },
+        _ => response::from_file(file, len, content_type),
+    };
+    conditional::set_etag(&mut resp, &etag);
+    if let Some(modified) = modified {
+        conditional::set_last_modified(&mut resp, modified);
+    }
+    // Browsers must not guess that a file from the vault is a page, which could run scripts
+    resp.headers_mut().insert(http::header::X_CONTENT_TYPE_OPTIONS, http::HeaderValue::from_static("nosniff"));
+    Ok(resp)
+}
+
+/// Respond with a static file built into the binary
+fn builtin_response(name: &str, mime_types: &MimeTypes) -> Response<Body> {
+    let content = match theme::static_file(Path::new(name)) {
+        Some((_, content)) => content,
+        None => return response::server_error(),
+    };
+    let mut resp = response::with_content_type(content.to_vec(), mime_types.guess(Path::new(name), content));
+    conditional::set_etag(&mut resp, &conditional::content_etag(content));
+    resp
+}
+
+/// Respond with a precompressed sibling of a file, such as `styles.css.gz` for `styles.css`
+fn precompressed_response(path: &Path, sibling: &Path, encoding: compression::Encoding, mime_types: &MimeTypes) -> Result<Response<Body>, std::io::Error> {
+    let content_type = content_type(path, &mut File::open(path)?, mime_types)?;
+    let file = File::open(sibling)?;
+    let meta = file.metadata()?;
+    let mut resp = response::from_file(file, meta.len(), content_type);
+    conditional::set_etag(&mut resp, &conditional::file_etag(&meta));
+    if let Ok(modified) = meta.modified() {
+        conditional::set_last_modified(&mut resp, modified);
+    }
+    // Ranges would refer to the uncompressed file
+    resp.headers_mut().remove(http::header::ACCEPT_RANGES);
+    resp.headers_mut().insert(http::header::X_CONTENT_TYPE_OPTIONS, http::HeaderValue::from_static("nosniff"));
+    compression::set_encoding(resp.headers_mut(), encoding);
+    Ok(resp)
+}
+
+/// The content type of a file, from its name or first few bytes
+fn content_type<'a>(path: &Path, file: &mut File, mime_types: &'a MimeTypes) -> Result<&'a str, std::io::Error> {
+    let mut head = Vec::with_capacity(mime::SNIFF_LEN);
+    file.take(mime::SNIFF_LEN as u64).read_to_end(&mut head)?;
+    Ok(mime_types.guess(path, &head))
 }
 
 /// Response for a found directory
-fn dir_response(path: &Path, accepts: Vec<AcceptFormat>, config: &Config, tera: &Tera) -> Response<Vec<u8>> {
-    let root_contents = walkdir::walk_dir(&config.rootdir , true)
-        .expect("Problem stripping prefix?");
-    if let Ok(dirtree) = walkdir::walk_dir(path, false) {
+///
+/// The listing is last modified whenever any directory in the tree is, and the html pages also
+/// include the tree from the root. The json listing also has front matter, from the link index.
+#[allow(clippy::needless_return)]
+fn dir_response(path: &Path, accepts: Vec<AcceptFormat>, links: &LinkIndex, config: &Config, tera: &Tera) -> Response<Body> {
+    let root_contents = nav_tree(config);
+    if let Ok(dirtree) = walkdir::walk_dir(path, None) {
         use AcceptFormat::*;
-        for af in accepts {
-            match af {
-                Json => return dir_json(dirtree, config),
-                PartialHtml => return dir_html(dirtree, root_contents, "directory-chunk.html", tera),
-                Html | Any => return dir_html(dirtree, root_contents, "directory.html", tera),
-            }
+        let (mut resp, modified) = match accepts.into_iter().next() {
+            Some(Json) => {
+                // The front matter comes from the link index, and changes with it. Before the
+                // index is built, the listing is not dated at all
+                let mut dirtree = dirtree;
+                dirtree.add_metadata(path, links);
+                let modified = links.last_modified()
+                    .map(|changed| walkdir::last_modified(path).map_or(changed, |m| m.max(changed)));
+                (dir_json(dirtree, config), modified)
+            },
+            Some(PartialHtml) => (dir_html(dirtree, root_contents, "directory-chunk.html", config, tera),
+                                  walkdir::last_modified(path)),
+            // Html, Any, or apparently no preferences. The page includes the whole tree, so it is
+            // only validated by its ETag
+            _ => (dir_html(dirtree, root_contents, "directory.html", config, tera), None),
+        };
+        if let (StatusCode::OK, Some(modified)) = (resp.status(), modified) {
+            conditional::set_last_modified(&mut resp, modified);
         }
-        // Apparently no preferences?
-        return dir_html(dirtree, root_contents, "directory.html", tera);
+        return resp;
     } else {
         return response::server_error();
     }
 }
 
-fn dir_html(dirtree: walkdir::Directory, root_contents: walkdir::Directory, template: &str, tera: &Tera) -> Response<Vec<u8>> {
+fn dir_html(dirtree: walkdir::Directory, root_contents: walkdir::Directory, template: &str, config: &Config, tera: &Tera) -> Response<Body> {
     let mut context = tera::Context::new();
     context.insert("dir_contents", &dirtree);
     context.insert("dirtree", &root_contents);
+    context.insert("base_path", &config.base_path);
     match tera.render(template, &context) {
-        Ok(rendered) => response::from_string(rendered),
+        Ok(rendered) => response::html(rendered),
         Err(e) => {eprintln!("{e}"); response::server_error()},
     }
 }
 
-fn dir_json(dirtree: walkdir::Directory,  _config: &Config) -> Response<Vec<u8>> {
+fn dir_json(dirtree: walkdir::Directory,  _config: &Config) -> Response<Body> {
     if let Ok(s) = serde_json::to_string(&dirtree) {
-        response::from_string(s)
+        response::json(s)
     } else {
         response::server_error()
     }
 }
 
-fn markdown_response(path: &Path, accepts: Vec<AcceptFormat>, config: &Config, tera: &Tera) -> Result<Response<Vec<u8>>, std::io::Error> {
-    for af in accepts {
-        use AcceptFormat::*;
-        match af {
-            PartialHtml => return markdown_response_naked(path),
-            Html | Any => return markdown_response_full(path, config, tera),
-            _ => continue,
-        }
+/// Respond with a markdown document, and the listing of its directory if it is an index
+///
+/// The document is only json when `json` is preferred (see `prefers_json`).
+#[allow(clippy::too_many_arguments)]
+fn markdown_response(path: &Path, accepts: Vec<AcceptFormat>, json: bool, listing: Option<walkdir::Directory>, resolver: &Resolver, links: &LinkIndex, config: &Config, tera: &Tera) -> Result<Response<Body>, std::io::Error> {
+    use AcceptFormat::*;
+    match accepts.first() {
+        Some(PartialHtml) => markdown_response_naked(path, resolver, config),
+        Some(_) if json => markdown_json(path, links),
+        Some(Html | Any | Json) => markdown_response_full(path, listing, resolver, links, config, tera),
+        None => Ok(response::not_allowed()),
     }
-    Ok(response::not_allowed())
 }
 
-/// Convert a markdown document into an HTML response
-fn markdown_response_full(path: &Path, config: &Config, tera: &Tera) -> Result<Response<Vec<u8>>, std::io::Error> {
-    // Load the markdown
-    let mut contents: String = String::new();
-    {
-        let mut file = BufReader::new(File::open(path)?);
-        file.read_to_string(&mut contents)?;
+/// The metadata of a markdown document and the notes linking to it, as json
+fn markdown_json(path: &Path, links: &LinkIndex) -> Result<Response<Body>, std::io::Error> {
+    let contents = fs::read_to_string(path)?;
+    let (meta, _) = frontmatter::split(&contents);
+    let json = serde_json::json!({
+        "meta": meta.unwrap_or_default(),
+        "backlinks": links.backlinks(path),
+    });
+    let mut resp = response::json(json.to_string());
+    // Before the index is built, the backlinks are missing, and the json is not dated
+    if let Some(changed) = links.last_modified() {
+        conditional::set_last_modified(&mut resp, mtime(path).map_or(changed, |m| m.max(changed)));
     }
-    // Parse the markdown
-    // NOTE(jladan): disable smart punctuation for latex
-    let options = Options::from_bits_truncate(0b1011110);
-    let parser = Parser::new_ext(&contents, options);
-    let mut html_out = String::new();
-    html::push_html(&mut html_out, parser);
+    Ok(resp)
+}
 
-    let root_contents = walkdir::walk_dir(&config.rootdir , true)
-        .expect("Problem stripping prefix?");
+/// Convert a markdown document into an HTML response
+///
+/// The front matter is available to the template as `meta`, the notes linking to the document as
+/// `backlinks`, and an index document's page also gets the directory listing, as `dir_contents`.
+fn markdown_response_full(path: &Path, listing: Option<walkdir::Directory>, resolver: &Resolver, links: &LinkIndex, config: &Config, tera: &Tera) -> Result<Response<Body>, std::io::Error> {
+    let (html_out, meta) = render_markdown(path, resolver, config)?;
+    let meta = meta.unwrap_or_default();
+    // The front matter may choose another template
+    let template = match meta.template.as_deref() {
+        Some(name) if tera.get_template_names().any(|t| t == name) => name,
+        Some(name) => {
+            eprintln!("{}: no template '{name}'", path.display());
+            MARKDOWN_TEMPLATE
+        },
+        None => MARKDOWN_TEMPLATE,
+    };
+
+    let root_contents = nav_tree(config);
     // Apply the template
     use tera::Context;
     let mut context = Context::new();
     context.insert("content", &html_out);
+    context.insert("meta", &meta);
+    context.insert("backlinks", &links.backlinks(path));
     context.insert("dirtree", &root_contents);
-    match tera.render(MARKDOWN_TEMPLATE, &context) {
-        Ok(html_out) => Ok(response::from_string(html_out)),
+    context.insert("base_path", &config.base_path);
+    if let Some(listing) = listing {
+        context.insert("dir_contents", &listing);
+    }
+    match tera.render(template, &context) {
+        // The page also changes with the directory tree and the notes linking to it, so it is only
+        // validated by the ETag of its content
+        Ok(html_out) => Ok(response::html(html_out)),
         Err(e) => {
             eprintln!("{e}");
             Ok(response::server_error())
@@ -224,21 +487,84 @@ fn markdown_response_full(path: &Path, config: &Config, tera: &Tera) -> Result<R
 }
 
 /// Convert a markdown document into an HTML response
-fn markdown_response_naked(path: &Path) -> Result<Response<Vec<u8>>, std::io::Error> {
-    // Load the markdown
+fn markdown_response_naked(path: &Path, resolver: &Resolver, config: &Config) -> Result<Response<Body>, std::io::Error> {
+    let (html_out, _) = render_markdown(path, resolver, config)?;
+
+    // Wiki-links change with the directory tree, so this is only validated by its ETag
+    Ok(response::html(html_out))
+}
+
+/// Load a markdown document and render it to html, resolving its wiki-links
+///
+/// Front matter is not rendered, but returned separately.
+fn render_markdown(path: &Path, resolver: &Resolver, config: &Config) -> Result<(String, Option<frontmatter::Metadata>), std::io::Error> {
     let mut contents: String = String::new();
     {
         let mut file = BufReader::new(File::open(path)?);
         file.read_to_string(&mut contents)?;
     }
-    // Parse the markdown
-    // NOTE(jladan): disable smart punctuation for latex
-    let options = Options::from_bits_truncate(0b1011110);
-    let parser = Parser::new_ext(&contents, options);
-    let mut html_out = String::new();
-    html::push_html(&mut html_out, parser);
+    let (meta, body) = frontmatter::split(&contents);
+    let options = config.markdown.to_options();
+    Ok((markdown::render(body, options, |target| resolver.wiki_link(target)), meta))
+}
 
-    Ok(response::from_string(html_out))
+/// The navigation tree: the web root, and the mounts that are not hidden
+fn nav_tree(config: &Config) -> walkdir::Directory {
+    let mut tree = walkdir::walk_dir(&config.rootdir, Some(&config.base_path))
+        .expect("Problem stripping prefix?");
+    for mount in config.mounts.iter().filter(|m| !m.hidden) {
+        let base = format!("{}{}", config.base_path, mount.prefix);
+        match walkdir::walk_dir(&mount.root, Some(&base)) {
+            Ok(mounted) => tree.add_mount(&mount.name, mounted),
+            Err(e) => eprintln!("{}: {e}", mount.root.display()),
+        }
+    }
+    tree
+}
+
+/// A tera error with its causes, which hold the actual problem
+fn template_error(e: &tera::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = std::error::Error::source(e);
+    while let Some(cause) = source {
+        message.push_str(&format!(": {cause}"));
+        source = cause.source();
+    }
+    message
+}
+
+fn mtime(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
 }
 
 // }}}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_accept_is_any() {
+        let mut headers = http::HeaderMap::new();
+        headers.insert("accept", http::HeaderValue::from_bytes(b"text/html\xff").unwrap());
+        assert!(matches!(preferred_format(&headers).as_slice(), [AcceptFormat::Any]));
+    }
+
+    #[test]
+    fn json_only_when_preferred() {
+        let prefers = |accept: &str| {
+            let mut headers = http::HeaderMap::new();
+            headers.insert("accept", http::HeaderValue::from_str(accept).unwrap());
+            prefers_json(&headers)
+        };
+        assert!(prefers("application/json"));
+        assert!(prefers("application/json, text/html;q=0.9"));
+        assert!(prefers("text/html;q=0.5, application/json"));
+        assert!(!prefers("application/json, */*"));
+        assert!(!prefers("application/json, text/html"));
+        assert!(!prefers("application/json;q=0.9, text/*"));
+        assert!(!prefers("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"));
+        assert!(!prefers("*/*"));
+        assert!(!prefers("application/json;q=0"));
+    }
+}
@@ -0,0 +1,187 @@
+//! Conditional requests
+//!
+//! Responses carry an `ETag` and, where there is a meaningful modification time, a
+//! `Last-Modified` header. Requests with `If-None-Match` or `If-Modified-Since` are answered
+//! with `304 Not Modified` if those validators still match.
+//!
+//! - Files use a strong ETag from their size and modification time, so they never need reading
+//! - Rendered pages and json use a hash of their content
+
+use std::{
+    fs::Metadata,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use http::{
+    HeaderMap,
+    HeaderValue,
+    StatusCode,
+    header::{
+        CACHE_CONTROL, CONTENT_LOCATION, ETAG, EXPIRES, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+        IF_RANGE, LAST_MODIFIED, VARY,
+    },
+};
+
+use crate::response::{Body, Response};
+
+/// Headers that are repeated in a 304 response (RFC 9110, section 15.4.5)
+const NOT_MODIFIED_HEADERS: [http::header::HeaderName; 6] =
+    [CACHE_CONTROL, CONTENT_LOCATION, ETAG, EXPIRES, LAST_MODIFIED, VARY];
+
+/// ETag for a file, based on its size and modification time
+pub fn file_etag(meta: &Metadata) -> String {
+    let mtime = meta.modified().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    format!("\"{:x}-{:x}\"", meta.len(), mtime)
+}
+
+/// ETag for generated content, based on a hash of the content
+pub fn content_etag(content: &[u8]) -> String {
+    // 64-bit FNV-1a, which is stable between builds unlike `DefaultHasher`
+    let hash = content.iter().fold(0xcbf29ce484222325u64, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("\"{hash:016x}\"")
+}
+
+/// Set the `ETag` header of a response
+pub fn set_etag<T>(resp: &mut Response<T>, etag: &str) {
+    if let Ok(etag) = HeaderValue::from_str(etag) {
+        resp.headers_mut().insert(ETAG, etag);
+    }
+}
+
+/// Set the `Last-Modified` header of a response
+pub fn set_last_modified<T>(resp: &mut Response<T>, time: SystemTime) {
+    let date = HeaderValue::from_str(&httpdate::fmt_http_date(time)).unwrap();
+    resp.headers_mut().insert(LAST_MODIFIED, date);
+}
+
+/// Give a successful response without an `ETag` one from a hash of its body, if it is in memory
+pub fn set_content_etag(resp: &mut Response<Body>) {
+    if !matches!(resp.status(), StatusCode::OK | StatusCode::PARTIAL_CONTENT) || resp.headers().contains_key(ETAG) {
+        return;
+    }
+    if let Body::Bytes(content) = resp.body() {
+        let etag = content_etag(content);
+        set_etag(resp, &etag);
+    }
+}
+
+/// Answer with `304 Not Modified` if the client's cached copy is still valid
+///
+/// Successful responses without an `ETag` get one from their content, see `set_content_etag`.
+pub fn evaluate(req_headers: &HeaderMap, mut resp: Response<Body>) -> Response<Body> {
+    if !matches!(resp.status(), StatusCode::OK | StatusCode::PARTIAL_CONTENT) {
+        return resp;
+    }
+    set_content_etag(&mut resp);
+    if !is_not_modified(req_headers, resp.headers()) {
+        return resp;
+    }
+    let mut not_modified = Response::builder()
+        .status(StatusCode::NOT_MODIFIED)
+        .body(Body::empty())
+        .unwrap();
+    for name in NOT_MODIFIED_HEADERS {
+        if let Some(value) = resp.headers().get(&name) {
+            not_modified.headers_mut().insert(name, value.clone());
+        }
+    }
+    not_modified
+}
+
+/// Whether the request's preconditions mean the cached copy is still valid
+///
+/// `If-None-Match` takes precedence, and `If-Modified-Since` is only used without it.
+fn is_not_modified(req_headers: &HeaderMap, resp_headers: &HeaderMap) -> bool {
+    if let Some(if_none_match) = req_headers.get(IF_NONE_MATCH) {
+        let etag = match resp_headers.get(ETAG).and_then(|v| v.to_str().ok()) {
+            Some(etag) => etag,
+            None => return false,
+        };
+        return if_none_match.to_str().map(|v| etag_list_matches(v, etag)).unwrap_or(false);
+    }
+    let since = req_headers.get(IF_MODIFIED_SINCE).and_then(parse_date);
+    let modified = resp_headers.get(LAST_MODIFIED).and_then(parse_date);
+    match (since, modified) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+/// Whether a `Range` request should be honoured given its `If-Range` header
+///
+/// The range only applies if the representation is unchanged: the ETag must match strongly, or
+/// the date must exactly equal the modification time.
+pub fn if_range_matches(req_headers: &HeaderMap, etag: &str, last_modified: Option<SystemTime>) -> bool {
+    let if_range = match req_headers.get(IF_RANGE).and_then(|v| v.to_str().ok()) {
+        Some(v) => v.trim(),
+        None => return true,
+    };
+    if if_range.starts_with('"') {
+        return !etag.starts_with("W/") && if_range == etag;
+    }
+    match (httpdate::parse_http_date(if_range), last_modified) {
+        (Ok(date), Some(modified)) => httpdate::fmt_http_date(modified) == httpdate::fmt_http_date(date),
+        _ => false,
+    }
+}
+
+/// Weak comparison of an ETag against an `If-None-Match` list
+fn etag_list_matches(list: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = opaque(etag);
+    list.split(',').any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+}
+
+fn parse_date(value: &HeaderValue) -> Option<SystemTime> {
+    httpdate::parse_http_date(value.to_str().ok()?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn headers(pairs: &[(http::header::HeaderName, &str)]) -> HeaderMap {
+        pairs.iter()
+            .map(|(k, v)| (k.clone(), HeaderValue::from_str(v).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn matching_etag_is_not_modified() {
+        let resp = Response::builder().body(Body::from(String::from("content"))).unwrap();
+        let etag = content_etag(b"content");
+        let req = headers(&[(IF_NONE_MATCH, &format!("\"other\", W/{etag}"))]);
+        let resp = evaluate(&req, resp);
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers().get(ETAG).unwrap(), etag.as_str());
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence() {
+        let modified = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut resp = Response::builder().body(Body::empty()).unwrap();
+        set_etag(&mut resp, "\"abc\"");
+        set_last_modified(&mut resp, modified);
+        let req = headers(&[
+            (IF_NONE_MATCH, "\"def\""),
+            (IF_MODIFIED_SINCE, &httpdate::fmt_http_date(modified)),
+        ]);
+        assert_eq!(evaluate(&req, resp).status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn compares_modification_dates() {
+        let modified = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let resp_headers = headers(&[(LAST_MODIFIED, &httpdate::fmt_http_date(modified))]);
+        let later = headers(&[(IF_MODIFIED_SINCE, &httpdate::fmt_http_date(modified + Duration::from_secs(60)))]);
+        let earlier = headers(&[(IF_MODIFIED_SINCE, &httpdate::fmt_http_date(modified - Duration::from_secs(60)))]);
+        assert!(is_not_modified(&later, &resp_headers));
+        assert!(!is_not_modified(&earlier, &resp_headers));
+    }
+}
@@ -68,6 +68,7 @@ impl TryFrom<fs::DirEntry> for Entry {
     }
 }
 
+#[allow(clippy::needless_return)]
 pub fn get_json(path: &Path) -> Result<String, DirError> {
     let entries = read_contents(path)?;
     return serde_json::to_string(&entries).map_err(DirError::from);
@@ -81,6 +82,7 @@ fn lift<T, E>(r: Result<Option<T>, E>) -> Option<Result<T, E>> {
     }
 }
 
+#[allow(clippy::needless_return, clippy::redundant_closure, clippy::needless_borrow, clippy::single_match)]
 pub fn read_contents(path: &Path) -> Result<Vec<Entry>, DirError> {
     let mut ret: Result<Vec<Entry>, DirError> = path.read_dir()?
         .map(|e| Entry::try_from(e))
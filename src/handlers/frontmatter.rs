@@ -0,0 +1,187 @@
+//! Front matter of markdown documents
+//!
+//! A document can start with metadata, either YAML between `---` lines or TOML between `+++`
+//! lines. It is removed from the rendered body and parsed into a `Metadata`.
+//!
+//! A `---` block that is not a YAML mapping is probably not front matter (`---` is also a
+//! thematic break), so the document is rendered as it is. One that starts like a mapping but
+//! cannot be parsed was meant to be front matter, so it is left out with a warning, like invalid
+//! TOML.
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// Metadata from the front matter of a document
+///
+/// The common keys have their own fields, and everything else is kept in `extra`. Lists may
+/// also be written as comma-separated strings, like `tags: rust, notes`.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Metadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+    /// The template to render the document with, instead of `markdown.html`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    pub draft: bool,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Split a document into its metadata, if any, and its body
+pub fn split(contents: &str) -> (Option<Metadata>, &str) {
+    let (front, body, is_toml) = match delimited(contents, "---", &["---", "..."]) {
+        Some((front, body)) => (front, body, false),
+        None => match delimited(contents, "+++", &["+++"]) {
+            Some((front, body)) => (front, body, true),
+            None => return (None, contents),
+        },
+    };
+    let fields = if is_toml {
+        toml::from_str::<toml::Table>(front).map(|table| toml_to_json(toml::Value::Table(table)))
+            .map_err(|e| e.to_string())
+    } else {
+        serde_yaml::from_str::<Value>(front).map_err(|e| e.to_string())
+    };
+    match fields {
+        Ok(Value::Object(fields)) => (Some(Metadata::from_fields(fields)), body),
+        // An empty block
+        Ok(Value::Null) if !is_toml => (Some(Metadata::default()), body),
+        Ok(_) => (None, contents),
+        // `+++` has no other meaning, and a first line like `key:` is a YAML mapping
+        Err(e) if is_toml || starts_like_mapping(front) => {
+            eprintln!("Invalid front matter: {e}");
+            (None, body)
+        },
+        Err(_) => (None, contents),
+    }
+}
+
+/// The text between an opening line and one of the closing lines, and the rest after it
+fn delimited<'a>(contents: &'a str, open: &str, close: &[&str]) -> Option<(&'a str, &'a str)> {
+    let rest = contents.strip_prefix('\u{feff}').unwrap_or(contents);
+    let rest = rest.strip_prefix(open)?;
+    let rest = rest.strip_prefix("\r\n").or_else(|| rest.strip_prefix('\n'))?;
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if close.contains(&line.trim_end()) {
+            return Some((&rest[..offset], &rest[offset + line.len()..]));
+        }
+        offset += line.len();
+    }
+    None
+}
+
+fn starts_like_mapping(yaml: &str) -> bool {
+    let first = yaml.lines().find(|line| !line.trim().is_empty() && !line.trim_start().starts_with('#'));
+    first.is_some_and(|line| line.contains(": ") || line.trim_end().ends_with(':'))
+}
+
+impl Metadata {
+    fn from_fields(mut fields: Map<String, Value>) -> Metadata {
+        let mut take_string = |key: &str| match fields.remove(key) {
+            Some(Value::String(s)) => Some(s),
+            Some(Value::Null) | None => None,
+            Some(other) => Some(other.to_string()),
+        };
+        let title = take_string("title");
+        let date = take_string("date");
+        let template = take_string("template");
+        let tags = list(fields.remove("tags"))
+            .into_iter()
+            .map(|tag| tag.trim_start_matches('#').to_string())
+            .collect();
+        let aliases = list(fields.remove("aliases"));
+        let draft = matches!(fields.remove("draft"), Some(Value::Bool(true)));
+        Metadata { title, tags, aliases, date, template, draft, extra: fields }
+    }
+}
+
+/// A list of strings, from a list or a comma-separated string
+fn list(value: Option<Value>) -> Vec<String> {
+    let items = match value {
+        Some(Value::Array(items)) => items,
+        Some(Value::String(s)) => s.split(',').map(|item| Value::String(item.to_string())).collect(),
+        Some(Value::Null) | None => return Vec::new(),
+        Some(other) => vec![other],
+    };
+    items.into_iter()
+        .map(|item| match item {
+            Value::String(s) => s.trim().to_string(),
+            other => other.to_string(),
+        })
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+fn toml_to_json(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => Value::from(i),
+        toml::Value::Float(f) => Value::from(f),
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Datetime(d) => Value::String(d.to_string()),
+        toml::Value::Array(items) => Value::Array(items.into_iter().map(toml_to_json).collect()),
+        toml::Value::Table(table) => Value::Object(table.into_iter().map(|(k, v)| (k, toml_to_json(v))).collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_yaml_front_matter() {
+        let doc = "---\ntitle: \"A: note\"\ntags:\n- rust\n- '#notes'\naliases: [first, second]\n\
+                   draft: true # for now\nsource:\n  url: https://example.com\n  pages: 12\n\
+                   summary: >\n  two\n  lines\n---\n# Body\n";
+        let (meta, body) = split(doc);
+        let meta = meta.unwrap();
+        assert_eq!(body, "# Body\n");
+        assert_eq!(meta.title.as_deref(), Some("A: note"));
+        assert_eq!(meta.tags, ["rust", "notes"]);
+        assert_eq!(meta.aliases, ["first", "second"]);
+        assert!(meta.draft);
+        assert_eq!(meta.extra["source"]["url"], "https://example.com");
+        assert_eq!(meta.extra["source"]["pages"], 12);
+        assert_eq!(meta.extra["summary"], "two lines\n");
+    }
+
+    #[test]
+    fn parses_all_of_yaml() {
+        let doc = "---\ntags: [\n  rust,\n  \"a, b\"\n]\nauthor: &me Jo\nreviewer: *me\n\
+                   links:\n  - name: home\n    url: /\n---\nBody";
+        let (meta, body) = split(doc);
+        let meta = meta.unwrap();
+        assert_eq!(body, "Body");
+        assert_eq!(meta.tags, ["rust", "a, b"]);
+        assert_eq!(meta.extra["reviewer"], "Jo");
+        assert_eq!(meta.extra["links"][0]["url"], "/");
+    }
+
+    #[test]
+    fn leaves_out_invalid_front_matter() {
+        assert_eq!(split("---\ntitle: [unclosed\n---\nBody"), (None, "Body"));
+    }
+
+    #[test]
+    fn splits_toml_front_matter() {
+        let (meta, body) = split("+++\ntitle = \"Note\"\ndate = 2023-05-01\ntags = \"a, b\"\n+++\nBody");
+        let meta = meta.unwrap();
+        assert_eq!((meta.title.as_deref(), meta.date.as_deref()), (Some("Note"), Some("2023-05-01")));
+        assert_eq!(meta.tags, ["a", "b"]);
+        assert_eq!(body, "Body");
+    }
+
+    #[test]
+    fn leaves_thematic_breaks_alone() {
+        let doc = "---\nJust some text\n---\nMore";
+        assert_eq!(split(doc), (None, doc));
+        assert_eq!(split("No front matter"), (None, "No front matter"));
+    }
+}
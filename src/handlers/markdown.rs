@@ -0,0 +1,380 @@
+//! Rendering markdown documents
+//!
+//! On top of what pulldown-cmark parses, wiki-links like `[[Note]]`, `[[Note|alias]]` and
+//! `[[Note#Heading]]` become anchors. Headings without an explicit `{#id}` get one made from
+//! their text, so a `#Heading` can be linked to. `links` finds the links of a document, for the
+//! backlinks index.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+};
+
+use pulldown_cmark::{escape, html, CowStr, Event, Options, Parser, Tag};
+
+/// Class of every wiki-link anchor
+pub const LINK_CLASS: &str = "wiki-link";
+/// Extra class of wiki-links to notes that do not exist
+pub const UNRESOLVED_CLASS: &str = "unresolved";
+/// Most characters of text kept around a link, see `links`
+pub const SNIPPET_LENGTH: usize = 160;
+
+/// Render markdown to html
+///
+/// `resolve` maps the target of a wiki-link (like `folder/Note`) to the link of the note, or to
+/// an error with the link the note would have if it does not exist.
+pub fn render(contents: &str, options: Options, resolve: impl Fn(&str) -> Result<String, String>) -> String {
+    let events = merge_text(contents, Parser::new_ext(contents, options).into_offset_iter());
+    let slugs = heading_slugs(&events);
+    let mut slugs = slugs.iter();
+    let mut in_code = false;
+    let mut rendered: Vec<Event> = Vec::with_capacity(events.len());
+    for (event, escaped) in events {
+        match event {
+            Event::Start(Tag::CodeBlock(kind)) => {
+                in_code = true;
+                rendered.push(Event::Start(Tag::CodeBlock(kind)));
+            },
+            Event::End(Tag::CodeBlock(kind)) => {
+                in_code = false;
+                rendered.push(Event::End(Tag::CodeBlock(kind)));
+            },
+            Event::Start(Tag::Heading(level, None, classes)) => {
+                let id = slugs.next().map(String::as_str);
+                rendered.push(Event::Start(Tag::Heading(level, id, classes)));
+            },
+            Event::Text(text) if !in_code && text.contains("[[") => {
+                wiki_links(&text, &escaped, &resolve, &mut rendered);
+            },
+            event => rendered.push(event),
+        }
+    }
+    let mut html_out = String::new();
+    html::push_html(&mut html_out, rendered.into_iter());
+    html_out
+}
+
+/// Join consecutive text events, since the brackets of a wiki-link are split from its text
+///
+/// Every event comes with the parts of its text that were escaped in the source (like `\[`, or an
+/// entity), which cannot be the brackets of a wiki-link.
+fn merge_text<'a>(contents: &str, events: impl Iterator<Item = (Event<'a>, Range<usize>)>) -> Vec<(Event<'a>, Vec<Range<usize>>)> {
+    let mut merged: Vec<(Event, Vec<Range<usize>>)> = Vec::new();
+    for (event, source) in events {
+        let escaped = match &event {
+            Event::Text(text) => escaped_part(contents, source, text),
+            _ => None,
+        };
+        match (merged.last_mut(), event) {
+            (Some((Event::Text(prev), prev_escaped)), Event::Text(text)) => {
+                let offset = prev.len();
+                prev_escaped.extend(escaped.map(|r| r.start + offset..r.end + offset));
+                *prev = CowStr::from(format!("{prev}{text}"));
+            },
+            (_, event) => merged.push((event, escaped.into_iter().collect())),
+        }
+    }
+    merged
+}
+
+/// The part of a text event that was escaped in the source: all of it for an entity, or its
+/// first character after a backslash
+fn escaped_part(contents: &str, source: Range<usize>, text: &str) -> Option<Range<usize>> {
+    if contents.get(source.clone()) != Some(text) {
+        return Some(0..text.len());
+    }
+    // A backslash can itself be escaped
+    let backslashes = contents[..source.start].bytes().rev().take_while(|b| *b == b'\\').count();
+    (backslashes % 2 == 1).then_some(0..1)
+}
+
+/// Ids for the headings without one, in order
+///
+/// The id is made from the text that is displayed. Headings without any, or whose id is already
+/// taken, get `section-N` for the Nth of them instead.
+fn heading_slugs(events: &[(Event, Vec<Range<usize>>)]) -> Vec<String> {
+    let mut taken: HashSet<String> = events.iter()
+        .filter_map(|(event, _)| match event {
+            Event::Start(Tag::Heading(_, Some(id), _)) => Some(id.to_string()),
+            _ => None,
+        })
+        .collect();
+    let mut slugs = Vec::new();
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    let mut heading: Option<String> = None;
+    for (event, escaped) in events {
+        match event {
+            Event::Start(Tag::Heading(_, None, _)) => heading = Some(String::new()),
+            Event::Text(text) => if let Some(heading) = heading.as_mut() {
+                for segment in wiki_segments(text, escaped) {
+                    heading.push_str(match segment {
+                        Segment::Text(text) => text,
+                        Segment::WikiLink(inner) => wiki_parts(inner).2,
+                    });
+                }
+            },
+            Event::Code(text) => if let Some(heading) = heading.as_mut() {
+                heading.push_str(text);
+            },
+            Event::End(Tag::Heading(..)) => if let Some(text) = heading.take() {
+                let slug = slugify(&text);
+                // Repeated headings get `-1`, `-2`, ... like other renderers do
+                let count = seen.entry(slug.clone()).or_insert(0);
+                let mut id = match *count {
+                    0 => slug.clone(),
+                    n => format!("{slug}-{n}"),
+                };
+                *count += 1;
+                if slug.is_empty() || taken.contains(&id) {
+                    id = (slugs.len() + 1..).map(|n| format!("section-{n}")).find(|id| !taken.contains(id)).unwrap();
+                }
+                taken.insert(id.clone());
+                slugs.push(id);
+            },
+            _ => (),
+        }
+    }
+    slugs
+}
+
+/// The id of a heading: lowercase, with dashes between words and without punctuation
+pub fn slugify(text: &str) -> String {
+    let mut slug = String::with_capacity(text.len());
+    for c in text.trim().chars() {
+        if c.is_alphanumeric() || c == '_' || c == '-' {
+            slug.extend(c.to_lowercase());
+        } else if c.is_whitespace() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug
+}
+
+/// Plain text, or the inside of a wiki-link
+enum Segment<'a> {
+    Text(&'a str),
+    WikiLink(&'a str),
+}
+
+/// Split text into plain text and wiki-links
+///
+/// Brackets in the `escaped` ranges of the text do not count.
+fn wiki_segments<'a>(text: &'a str, escaped: &[Range<usize>]) -> Vec<Segment<'a>> {
+    let literal = |at: usize| !escaped.iter().any(|r| r.contains(&at) || r.contains(&(at + 1)));
+    let mut segments = Vec::new();
+    // Where the text that is not in a segment yet starts, and how much is known to be plain text
+    let mut rest = 0;
+    let mut plain = 0;
+    while let Some(start) = text[plain..].find("[[").map(|start| plain + start) {
+        if !literal(start) {
+            plain = start + 1;
+            continue;
+        }
+        let end = text[start + 2..].match_indices("]]")
+            .map(|(end, _)| start + 2 + end)
+            .find(|end| literal(*end));
+        let end = match end {
+            Some(end) => end,
+            None => break,
+        };
+        let inner = &text[start + 2..end];
+        // Not a link, like `[[` in prose, or a nested `[[[[`
+        if inner.trim().is_empty() || inner.contains(['[', '\n']) {
+            plain = start + 2;
+            continue;
+        }
+        if start > rest {
+            segments.push(Segment::Text(&text[rest..start]));
+        }
+        segments.push(Segment::WikiLink(inner));
+        rest = end + 2;
+        plain = rest;
+    }
+    if rest < text.len() {
+        segments.push(Segment::Text(&text[rest..]));
+    }
+    segments
+}
+
+/// Split text into plain text and wiki-link anchors
+fn wiki_links<'a>(text: &str, escaped: &[Range<usize>], resolve: &impl Fn(&str) -> Result<String, String>, out: &mut Vec<Event<'a>>) {
+    for segment in wiki_segments(text, escaped) {
+        out.push(match segment {
+            Segment::Text(text) => Event::Text(CowStr::from(text.to_string())),
+            Segment::WikiLink(inner) => Event::Html(CowStr::from(anchor(inner, resolve))),
+        });
+    }
+}
+
+/// The note, heading and label of the inside of `[[...]]`
+fn wiki_parts(inner: &str) -> (&str, Option<&str>, &str) {
+    let (target, label) = match inner.split_once('|') {
+        Some((target, label)) => (target.trim(), label.trim()),
+        None => (inner.trim(), inner.trim()),
+    };
+    match target.split_once('#') {
+        Some((note, heading)) => (note.trim(), Some(heading.trim()), label),
+        None => (target, None, label),
+    }
+}
+
+/// The anchor for the inside of `[[...]]`
+fn anchor(inner: &str, resolve: &impl Fn(&str) -> Result<String, String>) -> String {
+    let (note, heading, label) = wiki_parts(inner);
+    let fragment = heading.map(|h| format!("#{}", slugify(h))).unwrap_or_default();
+    let (href, class) = match note {
+        // A link to a heading on the same page
+        "" => (fragment, LINK_CLASS.to_string()),
+        note => match resolve(note) {
+            Ok(link) => (link + &fragment, LINK_CLASS.to_string()),
+            Err(link) => (link + &fragment, format!("{LINK_CLASS} {UNRESOLVED_CLASS}")),
+        },
+    };
+    let mut html_out = format!("<a class=\"{class}\" href=\"");
+    // Writing to a string cannot fail
+    let _ = escape::escape_href(&mut html_out, &href);
+    html_out.push_str("\">");
+    let _ = escape::escape_html(&mut html_out, label);
+    html_out.push_str("</a>");
+    html_out
+}
+
+/// Where a link in a document points
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkTarget {
+    /// The destination of a markdown link, as written
+    Url(String),
+    /// The note of a wiki-link, like `folder/Note`
+    Wiki(String),
+}
+
+/// The links in a document, each with the text of the block (paragraph, list item, ...) around it
+///
+/// Links to headings on the same page are left out.
+pub fn links(contents: &str, options: Options) -> Vec<(LinkTarget, String)> {
+    let mut links = Vec::new();
+    // The links of the current block, with where they are in its text
+    let mut pending: Vec<(LinkTarget, usize)> = Vec::new();
+    let mut text = String::new();
+    let mut in_code = false;
+    let mut flush = |pending: &mut Vec<(LinkTarget, usize)>, text: &mut String| {
+        for (target, at) in pending.drain(..) {
+            links.push((target, snippet(text, at)));
+        }
+        text.clear();
+    };
+    for (event, escaped) in merge_text(contents, Parser::new_ext(contents, options).into_offset_iter()) {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => in_code = true,
+            Event::End(Tag::CodeBlock(_)) => in_code = false,
+            Event::Start(Tag::Paragraph | Tag::Heading(..) | Tag::Item | Tag::TableHead | Tag::TableRow)
+                | Event::End(Tag::Paragraph | Tag::Heading(..) | Tag::Item | Tag::TableHead | Tag::TableRow) => {
+                flush(&mut pending, &mut text);
+            },
+            Event::Start(Tag::Link(_, dest, _)) if !dest.starts_with('#') => {
+                pending.push((LinkTarget::Url(dest.to_string()), text.len()));
+            },
+            Event::Text(t) if !in_code => for segment in wiki_segments(&t, &escaped) {
+                match segment {
+                    Segment::Text(t) => text.push_str(t),
+                    Segment::WikiLink(inner) => {
+                        let (note, _, label) = wiki_parts(inner);
+                        if !note.is_empty() {
+                            pending.push((LinkTarget::Wiki(note.to_string()), text.len()));
+                        }
+                        text.push_str(label);
+                    },
+                }
+            },
+            Event::Code(t) => text.push_str(&t),
+            Event::SoftBreak | Event::HardBreak | Event::End(Tag::TableCell) => text.push(' '),
+            _ => (),
+        }
+    }
+    flush(&mut pending, &mut text);
+    links
+}
+
+/// The text around byte `at`, at most `SNIPPET_LENGTH` characters of it
+fn snippet(text: &str, at: usize) -> String {
+    let text = text.trim_end();
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    if chars.len() <= SNIPPET_LENGTH {
+        return text.trim_start().to_string();
+    }
+    let at = chars.partition_point(|(i, _)| *i < at);
+    let start = at.saturating_sub(SNIPPET_LENGTH / 2).min(chars.len() - SNIPPET_LENGTH);
+    let end = start + SNIPPET_LENGTH;
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+    snippet.extend(chars[start..end].iter().map(|(_, c)| c));
+    if end < chars.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(note: &str) -> Result<String, String> {
+        match note {
+            "Some Note" => Ok(String::from("/notes/Some%20Note.md")),
+            _ => Err(format!("/{note}")),
+        }
+    }
+
+    #[test]
+    fn renders_wiki_links() {
+        let html = render("See [[Some Note]], [[Some Note#A Heading|there]] and [[Missing]].",
+                          Options::empty(), resolve);
+        assert_eq!(html, concat!(
+            "<p>See <a class=\"wiki-link\" href=\"/notes/Some%20Note.md\">Some Note</a>, ",
+            "<a class=\"wiki-link\" href=\"/notes/Some%20Note.md#a-heading\">there</a> and ",
+            "<a class=\"wiki-link unresolved\" href=\"/Missing\">Missing</a>.</p>\n",
+        ));
+        // Code is left alone
+        assert_eq!(render("`[[Some Note]]`", Options::empty(), resolve), "<p><code>[[Some Note]]</code></p>\n");
+    }
+
+    #[test]
+    fn finds_links() {
+        let doc = "# Intro\nSee [[Some Note|this note]] and [that](other.md#top).\n\n\
+                   - a [[#heading]] and [[folder/Note#Heading]]\n\n```\n[[Code]]\n```\n";
+        assert_eq!(links(doc, Options::empty()), [
+            (LinkTarget::Wiki(String::from("Some Note")), String::from("See this note and that.")),
+            (LinkTarget::Url(String::from("other.md#top")), String::from("See this note and that.")),
+            (LinkTarget::Wiki(String::from("folder/Note")), String::from("a #heading and folder/Note#Heading")),
+        ]);
+        let long = format!("{} [[Note]] {}", "a".repeat(200), "b".repeat(200));
+        let (_, snippet) = &links(&long, Options::empty())[0];
+        assert_eq!(snippet.chars().count(), SNIPPET_LENGTH + 2);
+        assert!(snippet.contains("a Note b"));
+    }
+
+    #[test]
+    fn escaped_brackets_are_text() {
+        assert_eq!(render("\\[\\[Some Note\\]\\] and \\[[Some Note]]", Options::empty(), resolve),
+                   "<p>[[Some Note]] and [[Some Note]]</p>\n");
+        assert!(links("\\[\\[Some Note\\]\\]", Options::empty()).is_empty());
+        // Other escapes in the text do not matter
+        assert_eq!(render("a \\* [[Missing]]", Options::empty(), resolve),
+                   "<p>a * <a class=\"wiki-link unresolved\" href=\"/Missing\">Missing</a></p>\n");
+    }
+
+    #[test]
+    fn headings_get_ids() {
+        let html = render("# A Heading\n## A heading!\n", Options::empty(), resolve);
+        assert_eq!(html, "<h1 id=\"a-heading\">A Heading</h1>\n<h2 id=\"a-heading-1\">A heading!</h2>\n");
+        // From the text that is displayed, or the position when there is none
+        let html = render("# [[Some Note|Shown]]\n#\n# Shown\n", Options::ENABLE_HEADING_ATTRIBUTES, resolve);
+        assert!(html.starts_with("<h1 id=\"shown\">"));
+        assert!(html.contains("<h1 id=\"section-2\"></h1>"));
+        assert!(html.contains("<h1 id=\"shown-1\">"));
+        let html = render("# A\n# A {#a-1}\n# A\n", Options::ENABLE_HEADING_ATTRIBUTES, resolve);
+        assert_eq!(html, "<h1 id=\"a\">A</h1>\n<h1 id=\"a-1\">A</h1>\n<h1 id=\"section-2\">A</h1>\n");
+    }
+}
@@ -0,0 +1,209 @@
+//! Handling `Range` requests
+//!
+//! Only byte ranges are supported. A single range is answered with a plain `206 Partial Content`
+//! response, and several ranges with a `multipart/byteranges` body.
+
+use std::{
+    fs::File,
+    ops::Range,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use http::{
+    StatusCode,
+    header::{ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE},
+};
+
+use crate::response::{Body, Response};
+
+/// Requests with more ranges than this are served in full, rather than as many tiny parts
+const MAX_RANGES: usize = 64;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeError {
+    /// The header could not be parsed, so it should be ignored
+    Invalid,
+    /// None of the ranges overlap the resource
+    Unsatisfiable,
+}
+
+/// Parse a `Range` header value into byte ranges of a resource with length `len`
+///
+/// Ranges are clamped to the length of the resource, and ranges that do not overlap it are
+/// dropped. Overlapping and adjacent ranges are merged.
+pub fn parse(header: &str, len: u64) -> Result<Vec<Range<u64>>, RangeError> {
+    let (unit, specs) = header.split_once('=').ok_or(RangeError::Invalid)?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return Err(RangeError::Invalid);
+    }
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (start, end) = spec.split_once('-').ok_or(RangeError::Invalid)?;
+        let number = |s: &str| s.trim().parse::<u64>().map_err(|_| RangeError::Invalid);
+        let range = match (start.trim().is_empty(), end.trim().is_empty()) {
+            // `bytes=-500` is the last 500 bytes
+            (true, false) => len.saturating_sub(number(end)?)..len,
+            // `bytes=500-` is everything from byte 500
+            (false, true) => number(start)?..len,
+            (false, false) => {
+                let (start, end) = (number(start)?, number(end)?);
+                if end < start {
+                    return Err(RangeError::Invalid);
+                }
+                start..end.saturating_add(1).min(len)
+            },
+            (true, true) => return Err(RangeError::Invalid),
+        };
+        if range.start < range.end {
+            ranges.push(range);
+        }
+    }
+    if ranges.is_empty() {
+        return Err(RangeError::Unsatisfiable);
+    }
+    if ranges.len() > MAX_RANGES {
+        return Err(RangeError::Invalid);
+    }
+    Ok(coalesce(ranges))
+}
+
+fn coalesce(mut ranges: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    if ranges.len() < 2 {
+        return ranges;
+    }
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Respond with only the requested ranges of a file
+///
+/// `ranges` must be non-empty, as returned by `parse`.
+pub fn partial_response(file: File, len: u64, ranges: Vec<Range<u64>>, content_type: &str)
+        -> Result<Response<Body>, std::io::Error> {
+    let builder = Response::builder()
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(ACCEPT_RANGES, "bytes");
+    if let [range] = ranges.as_slice() {
+        let body = Body::File { file, offset: range.start, len: range.end - range.start };
+        return Ok(builder
+            .header(CONTENT_RANGE, content_range(range, len))
+            .header(CONTENT_LENGTH, range.end - range.start)
+            .header(CONTENT_TYPE, content_type)
+            .body(body)
+            .unwrap());
+    }
+    let boundary = boundary();
+    let mut parts = Vec::with_capacity(2 * ranges.len() + 1);
+    for range in ranges.iter() {
+        let part_header = format!("\r\n--{boundary}\r\n{}: {content_type}\r\n{}: {}\r\n\r\n",
+            CONTENT_TYPE, CONTENT_RANGE, content_range(range, len));
+        parts.push(Body::from(part_header));
+        parts.push(Body::File { file: file.try_clone()?, offset: range.start, len: range.end - range.start });
+    }
+    parts.push(Body::from(format!("\r\n--{boundary}--\r\n")));
+    let body = Body::Parts(parts);
+    Ok(builder
+        .header(CONTENT_LENGTH, body.len().unwrap())
+        .header(CONTENT_TYPE, format!("multipart/byteranges; boundary={boundary}"))
+        .body(body)
+        .unwrap())
+}
+
+/// Respond to a range request that does not overlap the file
+pub fn not_satisfiable(len: u64) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+        .header(CONTENT_RANGE, format!("bytes */{len}"))
+        .header(ACCEPT_RANGES, "bytes")
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn content_range(range: &Range<u64>, len: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, len)
+}
+
+/// A boundary that is unlikely to appear in the file contents
+fn boundary() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    format!("smd-byteranges-{nanos:x}")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::response;
+    use super::*;
+
+    fn single(range: Range<u64>) -> Result<Vec<Range<u64>>, RangeError> {
+        Ok(vec![range])
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(parse("bytes=0-499", 1000), single(0..500));
+        assert_eq!(parse("bytes=500-", 1000), single(500..1000));
+        assert_eq!(parse("bytes=-200", 1000), single(800..1000));
+        assert_eq!(parse("bytes=900-5000", 1000), single(900..1000));
+    }
+
+    #[test]
+    fn merges_overlapping_ranges() {
+        assert_eq!(parse("bytes=500-599, 0-99, 50-149", 1000), Ok(vec![0..150, 500..600]));
+    }
+
+    #[test]
+    fn rejects_bad_ranges() {
+        assert_eq!(parse("bytes=1000-", 1000), Err(RangeError::Unsatisfiable));
+        assert_eq!(parse("bytes=-0", 1000), Err(RangeError::Unsatisfiable));
+        assert_eq!(parse("bytes=5-2", 1000), Err(RangeError::Invalid));
+        assert_eq!(parse("items=0-5", 1000), Err(RangeError::Invalid));
+        assert_eq!(parse("bytes=a-b", 1000), Err(RangeError::Invalid));
+    }
+
+    /// The body of a response, as it is sent
+    fn sent_body(resp: Response<Body>) -> Vec<u8> {
+        let mut out = Vec::new();
+        response::write_response(resp, &mut out, http::Version::HTTP_11).unwrap();
+        let start = out.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        out.split_off(start)
+    }
+
+    #[test]
+    fn serves_multipart_byteranges() {
+        let dir = crate::test_util::sandbox("range");
+        let path = dir.join("letters.txt");
+        fs::write(&path, "0123456789abcdefghij").unwrap();
+        let ranges = parse("bytes=0-3, 10-", 20).unwrap();
+        let resp = partial_response(File::open(&path).unwrap(), 20, ranges, "text/plain").unwrap();
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        let content_type = resp.headers()[CONTENT_TYPE].to_str().unwrap().to_string();
+        let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap().to_string();
+        let len: usize = resp.headers()[CONTENT_LENGTH].to_str().unwrap().parse().unwrap();
+
+        let body = sent_body(resp);
+        assert_eq!(body.len(), len);
+        assert_eq!(String::from_utf8(body).unwrap(), format!(concat!(
+            "\r\n--{0}\r\ncontent-type: text/plain\r\ncontent-range: bytes 0-3/20\r\n\r\n0123",
+            "\r\n--{0}\r\ncontent-type: text/plain\r\ncontent-range: bytes 10-19/20\r\n\r\nabcdefghij",
+            "\r\n--{0}--\r\n",
+        ), boundary));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges_give_the_length() {
+        let resp = not_satisfiable(20);
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(resp.headers()[CONTENT_RANGE], "bytes */20");
+        assert!(sent_body(resp).is_empty());
+    }
+}
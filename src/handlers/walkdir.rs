@@ -4,13 +4,17 @@
 
 use std::{
     path::{Path, PathBuf, StripPrefixError}, 
-    ffi::{OsStr, OsString}
+    ffi::{OsStr, OsString},
+    time::SystemTime,
 };
 
 use walkdir::{WalkDir, DirEntry};
 
 use serde::Serialize;
 
+use super::frontmatter::Metadata;
+use crate::links::LinkIndex;
+
 /*
 FS-Tree representation
 ----------------------
@@ -64,6 +68,9 @@ pub struct Directory {
 pub struct File { 
     name: String, 
     path: String,
+    /// Front matter of markdown files, if it has been read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    meta: Option<Metadata>,
 }
 
 impl Directory {
@@ -77,11 +84,31 @@ impl Directory {
     }
 }
 
+impl Directory {
+    /// Add the front matter of every markdown file in a tree from `walk_dir(dir, None)`, as the
+    /// link index last read it
+    pub fn add_metadata(&mut self, dir: &Path, links: &LinkIndex) {
+        for file in self.files.iter_mut().filter(|f| f.path.ends_with(".md")) {
+            file.meta = links.metadata(&dir.join(&file.path));
+        }
+        for subdir in self.dirs.iter_mut() {
+            subdir.add_metadata(dir, links);
+        }
+    }
+
+    /// Add the tree of a mount, under its own name
+    pub fn add_mount(&mut self, name: &str, mut tree: Directory) {
+        tree.name = name.to_string();
+        self.dirs.push(tree);
+    }
+}
+
 impl File {
     fn new(name: &OsStr, path: OsString) -> Self {
         Self { 
             name: name.to_string_lossy().to_string(), 
             path: path.to_string_lossy().to_string(),
+            meta: None,
         }
     }
 }
@@ -100,13 +127,21 @@ tree. This would inherently be recursive.
 
 Because walkdir is almost a depth-first iterator, it will be much easier to build the tree iteratively using a stack.
 */
-pub fn walk_dir(path: &Path, absolute: bool) -> Result<Directory, StripPrefixError> {
+/// Read the tree under `path`
+///
+/// Paths in the tree are relative to `path`, unless `base` is given. Then they are links, like
+/// `{base}/dir/file.md`, where `base` is the (possibly empty) path the site is served under.
+#[allow(clippy::needless_return)]
+pub fn walk_dir(path: &Path, base: Option<&str>) -> Result<Directory, StripPrefixError> {
     let prefix = path;      // Prefix to strip from all paths
+    let absolute = base.is_some();
+    let base = base.unwrap_or("");
     let mut dirstack: Vec<Directory> = Vec::new();
+    // The root itself is never hidden, even if it is named like `./`
     let mut walker = WalkDir::new(prefix)
         .sort_by(|a,b| a.file_name().to_ascii_lowercase().cmp(&b.file_name().to_ascii_lowercase()))
         .into_iter()
-        .filter_entry(|e| !is_hidden(e))
+        .filter_entry(|e| e.depth() == 0 || !is_hidden(e))
         .filter_map(|e| e.ok());
     let mut curdir: Directory = if let Some(entry) = walker.next() {
         let stripped = entry.path().strip_prefix(prefix)?;
@@ -122,7 +157,7 @@ pub fn walk_dir(path: &Path, absolute: bool) -> Result<Directory, StripPrefixErr
             while let Some(mut prevdir) = dirstack.pop() {
                 // Add the current directory to its parent
                 format_dir(&mut curdir.path);
-                if absolute { *curdir.path.as_mut_os_string() = make_abs(&curdir.path) }
+                if absolute { *curdir.path.as_mut_os_string() = make_abs(&curdir.path, base) }
                 prevdir.dirs.push(curdir);
                 curdir = prevdir;
                 // Continue until we've found the parent
@@ -134,7 +169,7 @@ pub fn walk_dir(path: &Path, absolute: bool) -> Result<Directory, StripPrefixErr
         // Now perform logic on current entry
         if entry.file_type().is_file() {
             let stripped = if absolute { 
-                make_abs(stripped)
+                make_abs(stripped, base)
             } else {
                 stripped.as_os_str().to_os_string()
             };
@@ -150,20 +185,34 @@ pub fn walk_dir(path: &Path, absolute: bool) -> Result<Directory, StripPrefixErr
     while let Some(mut prevdir) = dirstack.pop() {
         // Add the current directory to its parent
         format_dir(&mut curdir.path);
-        if absolute { *curdir.path.as_mut_os_string() = make_abs(&curdir.path) }
+        if absolute { *curdir.path.as_mut_os_string() = make_abs(&curdir.path, base) }
         prevdir.dirs.push(curdir);
         curdir = prevdir;
     }
-    if absolute { *curdir.path.as_mut_os_string() = make_abs(&curdir.path) }
+    if absolute { *curdir.path.as_mut_os_string() = make_abs(&curdir.path, base) }
     return Ok(curdir)
 }
 
+/// The latest modification time of any directory in the tree
+///
+/// A directory is modified whenever an entry is added, removed or renamed, so this is the last
+/// time the tree returned by `walk_dir` could have changed.
+pub fn last_modified(path: &Path) -> Option<SystemTime> {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_entry(|e| e.file_type().is_dir() && (e.depth() == 0 || !is_hidden(e)))
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.metadata().ok()?.modified().ok())
+        .max()
+}
+
 fn format_dir(a: &mut PathBuf) {
     a.as_mut_os_string().push("/");
 }
 
-fn make_abs(a: &Path) -> OsString {
-    let mut built = OsString::with_capacity(a.as_os_str().len() + 1);
+fn make_abs(a: &Path, base: &str) -> OsString {
+    let mut built = OsString::with_capacity(base.len() + a.as_os_str().len() + 1);
+    built.push(base);
     built.push("/");
     built.push(a.as_os_str());
     built
@@ -1,5 +1,16 @@
 pub mod handlers;
 pub mod request;
 pub mod response;
+pub mod compression;
 pub mod config;
+pub mod mime;
 pub mod uri;
+pub mod shutdown;
+pub mod server;
+pub mod listener;
+pub mod theme;
+pub mod links;
+#[cfg(test)]
+mod test_util;
+#[cfg(feature = "tls")]
+pub mod tls;
@@ -0,0 +1,424 @@
+//! The index of links between notes, for backlinks
+//!
+//! Every markdown document in the web root and the mounts is scanned for its front matter, and
+//! for markdown links and wiki-links, which are resolved to the documents they point to (following smart links). `watch`
+//! builds the index, and refreshes it on a background thread whenever a note changes, reading
+//! again only the documents that changed. Requests get the backlinks of the last refresh.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io,
+    mem,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, RwLock, Weak},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use notify::{RecursiveMode, Watcher};
+use pulldown_cmark::Options;
+use serde::Serialize;
+use url_escape::{decode as decode_url, encode_path};
+
+use crate::{
+    handlers::{frontmatter::{self, Metadata}, markdown::{self, LinkTarget}},
+    uri::{self, Resolved, Resolver},
+};
+
+/// How often the vault is checked for changes, when they cannot be watched
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+/// How long to wait for more changes before refreshing, since saving a note often makes several
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+/// A link to a note, from another note
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Backlink {
+    /// The title of the linking note, from its front matter or its file name
+    pub title: String,
+    pub link: String,
+    /// The text around the link
+    pub snippet: String,
+}
+
+pub struct LinkIndex {
+    resolver: Arc<Resolver>,
+    options: Options,
+    state: RwLock<State>,
+}
+
+/// The index as of one refresh
+#[derive(Default)]
+struct State {
+    notes: HashMap<PathBuf, Arc<Note>>,
+    /// The notes linking to each document, by title
+    backlinks: HashMap<PathBuf, Vec<Backlink>>,
+    /// When the refresh found something changed, `None` before the first one
+    changed: Option<SystemTime>,
+}
+
+/// What the index knows about one document
+#[derive(Clone)]
+struct Note {
+    modified: Option<SystemTime>,
+    meta: Option<Metadata>,
+    title: String,
+    link: String,
+    links: Vec<Link>,
+}
+
+#[derive(Clone)]
+struct Link {
+    target: LinkTarget,
+    snippet: String,
+    /// The document linked to, if it exists
+    resolved: Option<PathBuf>,
+}
+
+impl LinkIndex {
+    /// An empty index, until it is refreshed
+    pub fn new(resolver: Arc<Resolver>, options: Options) -> LinkIndex {
+        LinkIndex { resolver, options, state: RwLock::new(State::default()) }
+    }
+
+    /// Read the documents that were added or changed since the last refresh, and forget the
+    /// removed ones
+    ///
+    /// When documents are added or removed, every link is resolved again, since a link to a
+    /// missing note may now point to the new one (or the other way around). The new index is
+    /// built without blocking requests, and then replaces the old one.
+    pub fn refresh(&self) {
+        self.resolver.refresh_names();
+        let (old, built) = {
+            let state = self.state.read().unwrap();
+            (state.notes.clone(), state.changed.is_some())
+        };
+        let documents = self.resolver.documents();
+        let mut notes = HashMap::with_capacity(documents.len());
+        let mut changed = false;
+        for path in documents {
+            let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+            match old.get(&path) {
+                Some(note) if modified.is_some() && note.modified == modified => {
+                    notes.insert(path, note.clone());
+                },
+                _ => {
+                    changed = true;
+                    if let Some(note) = self.read_note(&path, modified) {
+                        notes.insert(path, Arc::new(note));
+                    }
+                },
+            }
+        }
+        let moved = notes.len() != old.len() || notes.keys().any(|path| !old.contains_key(path));
+        if built && !changed && !moved {
+            return;
+        }
+        if moved {
+            for (path, note) in notes.iter_mut() {
+                let mut resolved = Note::clone(note);
+                for link in resolved.links.iter_mut() {
+                    link.resolved = self.resolve(path, &note.link, &link.target);
+                }
+                *note = Arc::new(resolved);
+            }
+        }
+        let state = State::new(notes);
+        // The old index is dropped after the lock is released
+        let _old = mem::replace(&mut *self.state.write().unwrap(), state);
+    }
+
+    /// The notes linking to a document, by title
+    pub fn backlinks(&self, path: &Path) -> Vec<Backlink> {
+        self.state.read().unwrap().backlinks.get(path).cloned().unwrap_or_default()
+    }
+
+    /// The front matter of a document
+    pub fn metadata(&self, path: &Path) -> Option<Metadata> {
+        self.state.read().unwrap().notes.get(path).and_then(|note| note.meta.clone())
+    }
+
+    /// When the index last changed, which is when backlinks could have changed
+    ///
+    /// This is the time of the refresh, so removed documents are accounted for too. It is `None`
+    /// until the index is built, when the backlinks are still missing.
+    pub fn last_modified(&self) -> Option<SystemTime> {
+        self.state.read().unwrap().changed
+    }
+
+    fn read_note(&self, path: &Path, modified: Option<SystemTime>) -> Option<Note> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                eprintln!("Could not index {}: {e}", path.display());
+                return None;
+            },
+        };
+        let link = self.resolver.link_to(path)?;
+        let (meta, body) = frontmatter::split(&contents);
+        let title = meta.as_ref().and_then(|meta| meta.title.clone())
+            .or_else(|| path.file_stem().map(|stem| stem.to_string_lossy().to_string()))
+            .unwrap_or_default();
+        let links = markdown::links(body, self.options).into_iter()
+            .map(|(target, snippet)| Link {
+                resolved: self.resolve(path, &link, &target),
+                target,
+                snippet,
+            })
+            .collect();
+        Some(Note { modified, meta, title, link, links })
+    }
+
+    /// The document a link in the note at `path` (served at `link`) points to
+    fn resolve(&self, path: &Path, link: &str, target: &LinkTarget) -> Option<PathBuf> {
+        let target = match target {
+            LinkTarget::Wiki(note) => self.resolver.wiki_link(note).ok()?,
+            LinkTarget::Url(url) => {
+                // Only links within the site, without the fragment or query
+                let url = url.split(['#', '?']).next().unwrap_or_default();
+                if url.is_empty() || url.starts_with("//") || has_scheme(url) {
+                    return None;
+                }
+                // Decoded first, since links to files with spaces are often left unencoded
+                let url = encode_path(&decode_url(url)).into_owned();
+                if url.starts_with('/') {
+                    url
+                } else {
+                    let dir = link.rsplit_once('/').map_or("", |(dir, _)| dir);
+                    format!("{dir}/{url}")
+                }
+            },
+        };
+        let found = self.document(&target);
+        // A note linking to itself is not a backlink
+        found.filter(|found| found != path)
+    }
+
+    /// The markdown document served at a link, from the notes found by the resolver, or the index
+    /// document of a directory
+    fn document(&self, link: &str) -> Option<PathBuf> {
+        if let Some(path) = self.resolver.note(link) {
+            return Some(path);
+        }
+        let location = match self.resolver.lookup(&link.parse().ok()?) {
+            Resolved::Index { document, .. } => return Some(document),
+            Resolved::Redirect(location) => location,
+            _ => return None,
+        };
+        match self.resolver.lookup(&location.parse().ok()?) {
+            Resolved::Index { document, .. } => Some(document),
+            _ => None,
+        }
+    }
+}
+
+impl State {
+    fn new(notes: HashMap<PathBuf, Arc<Note>>) -> State {
+        let mut backlinks: HashMap<PathBuf, Vec<Backlink>> = HashMap::new();
+        for note in notes.values() {
+            for link in note.links.iter() {
+                if let Some(target) = &link.resolved {
+                    backlinks.entry(target.clone()).or_default().push(Backlink {
+                        title: note.title.clone(),
+                        link: note.link.clone(),
+                        snippet: link.snippet.clone(),
+                    });
+                }
+            }
+        }
+        for links in backlinks.values_mut() {
+            links.sort_by_cached_key(|b| (b.title.to_lowercase(), b.link.clone()));
+            links.dedup();
+        }
+        State { notes, backlinks, changed: Some(SystemTime::now()) }
+    }
+}
+
+/// Whether a link starts with a scheme, like `https:` or `mailto:`
+fn has_scheme(url: &str) -> bool {
+    match url.split_once(':') {
+        Some((scheme, _)) => !scheme.is_empty() && !scheme.contains('/')
+            && scheme.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.')),
+        None => false,
+    }
+}
+
+/// Build the index, and refresh it on a background thread when the vault changes, until the
+/// index is dropped
+///
+/// The index is ready when this returns. Only the directories that may have notes are watched
+/// (not hidden or build directories), and only changes to notes, or to the directories, cause a
+/// refresh. If the vault cannot be watched, it is checked every `REFRESH_INTERVAL` instead.
+pub fn watch(index: &Arc<LinkIndex>) -> io::Result<()> {
+    let (sender, events) = mpsc::channel();
+    let watch = notify::recommended_watcher(move |event| {
+        let _ = sender.send(event);
+    });
+    let watch = watch.map(|watcher| {
+        let mut watch = Watch { watcher, dirs: HashSet::new(), incomplete: false };
+        watch.sync(&index.resolver);
+        watch
+    });
+    // Watching first, so nothing is missed between the refresh and the watches
+    index.refresh();
+    let index = Arc::downgrade(index);
+    let thread = thread::Builder::new().name(String::from("smd-links"));
+    match watch {
+        Ok(mut watch) => thread.spawn(move || {
+            while let Some(change) = watch.wait(&events, &index) {
+                let Some(live) = index.upgrade() else { break };
+                if change == Change::Dirs {
+                    watch.sync(&live.resolver);
+                }
+                live.refresh();
+            }
+        })?,
+        Err(e) => {
+            eprintln!("Could not watch for changes, checking every {}s instead: {e}", REFRESH_INTERVAL.as_secs());
+            thread.spawn(move || loop {
+                thread::sleep(REFRESH_INTERVAL);
+                match index.upgrade() {
+                    Some(live) => live.refresh(),
+                    None => break,
+                }
+            })?
+        },
+    };
+    Ok(())
+}
+
+/// What a change to the vault needs, besides refreshing the index
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Change {
+    Nothing,
+    Refresh,
+    /// The directories to watch may have changed too
+    Dirs,
+}
+
+/// The watches on every directory that may have notes
+///
+/// Each directory is watched on its own, so that hidden and build directories are left out.
+struct Watch {
+    watcher: notify::RecommendedWatcher,
+    dirs: HashSet<PathBuf>,
+    /// Whether a directory could not be watched, so changes may be missed
+    incomplete: bool,
+}
+
+impl Watch {
+    /// Watch the directories that were added, and forget the removed ones
+    fn sync(&mut self, resolver: &Resolver) {
+        let dirs: HashSet<PathBuf> = resolver.document_dirs().into_iter().collect();
+        for removed in self.dirs.difference(&dirs) {
+            // The watch is usually gone with the directory already
+            let _ = self.watcher.unwatch(removed);
+        }
+        self.dirs.retain(|dir| dirs.contains(dir));
+        self.incomplete = false;
+        for dir in dirs {
+            if self.dirs.contains(&dir) {
+                continue;
+            }
+            match self.watcher.watch(&dir, RecursiveMode::NonRecursive) {
+                Ok(()) => {
+                    self.dirs.insert(dir);
+                },
+                Err(e) => {
+                    eprintln!("Could not watch {} for changes: {e}", dir.display());
+                    self.incomplete = true;
+                },
+            }
+        }
+    }
+
+    /// Wait for a change to the vault, and the ones that come right after it
+    ///
+    /// Returns `None` once the index is dropped. While some directory is not watched, the vault
+    /// is checked every `REFRESH_INTERVAL` anyway.
+    fn wait(&self, events: &mpsc::Receiver<notify::Result<notify::Event>>, index: &Weak<LinkIndex>) -> Option<Change> {
+        let mut change = Change::Nothing;
+        while change == Change::Nothing {
+            match events.recv_timeout(REFRESH_INTERVAL) {
+                Ok(event) => change = self.change(&event),
+                Err(mpsc::RecvTimeoutError::Timeout) if index.strong_count() == 0 => return None,
+                Err(mpsc::RecvTimeoutError::Timeout) if self.incomplete => change = Change::Dirs,
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+                Err(mpsc::RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+        while let Ok(event) = events.recv_timeout(SETTLE_TIME) {
+            change = change.max(self.change(&event));
+        }
+        Some(change)
+    }
+
+    /// What one event needs: a note that changed needs a refresh, and a directory that was added
+    /// or removed needs the watches updated too
+    fn change(&self, event: &notify::Result<notify::Event>) -> Change {
+        let event = match event {
+            Ok(event) => event,
+            // Changes may have been missed
+            Err(_) => return Change::Dirs,
+        };
+        if matches!(event.kind, notify::EventKind::Access(_)) {
+            return Change::Nothing;
+        }
+        event.paths.iter()
+            .filter(|path| !path.file_name().is_some_and(uri::is_hidden))
+            .map(|path| {
+                if self.dirs.contains(path) || path.is_dir() {
+                    Change::Dirs
+                } else if path.extension().is_some_and(|ext| ext == "md") {
+                    Change::Refresh
+                } else {
+                    Change::Nothing
+                }
+            })
+            .max()
+            .unwrap_or(Change::Nothing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::{config::Config, test_util};
+    use super::*;
+
+    #[test]
+    fn finds_backlinks() {
+        let dir = test_util::sandbox("links");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("target.md"), "# Target\n").unwrap();
+        fs::write(dir.join("a.md"), "---\ntitle: First\n---\nSee [[Target]] and [[Later]].\n").unwrap();
+        fs::write(dir.join("sub/b.md"), "- [up](../target.md#top)\n- [away](https://example.com/target.md)\n").unwrap();
+        let config = Config::build().set_root(dir.to_str().unwrap()).build();
+        let index = LinkIndex::new(Arc::new(Resolver::new(&config)), Options::empty());
+        assert_eq!(index.last_modified(), None);
+        index.refresh();
+        assert!(index.last_modified().is_some());
+
+        let backlinks = index.backlinks(&dir.join("target.md"));
+        assert_eq!(backlinks, [
+            Backlink { title: String::from("b"), link: String::from("/sub/b.md"), snippet: String::from("up") },
+            Backlink { title: String::from("First"), link: String::from("/a.md"), snippet: String::from("See Target and Later.") },
+        ]);
+
+        // A new note is found, and so are the links to it that were missing
+        fs::write(dir.join("later.md"), "Back to [[a]], or [down](sub)").unwrap();
+        fs::write(dir.join("sub/README.md"), "# Sub").unwrap();
+        index.refresh();
+        assert_eq!(index.backlinks(&dir.join("later.md")).len(), 1);
+        assert_eq!(index.backlinks(&dir.join("a.md"))[0].link, "/later.md");
+        // A link to a directory is one to its index document
+        assert_eq!(index.backlinks(&dir.join("sub/README.md"))[0].snippet, "Back to a, or down");
+
+        // Removing a note changes the index, even though no remaining note changed
+        let before = index.last_modified();
+        fs::remove_file(dir.join("later.md")).unwrap();
+        index.refresh();
+        assert!(index.backlinks(&dir.join("a.md")).is_empty());
+        assert!(index.last_modified() > before);
+    }
+}
@@ -0,0 +1,277 @@
+//! Listening sockets
+//!
+//! The server accepts connections on TCP sockets, Unix domain sockets, or sockets passed in
+//! by systemd socket activation (`LISTEN_FDS`). `Listener` and `Connection` hide which kind is
+//! in use, so connections are handled the same way, including when they are wrapped in TLS.
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    time::Duration,
+};
+
+#[cfg(feature = "tls")]
+use std::sync::{Arc, Mutex};
+
+#[cfg(unix)]
+use std::{
+    env,
+    fs,
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        io::{FromRawFd, IntoRawFd, RawFd},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+};
+
+/// The first file descriptor passed by systemd (`SD_LISTEN_FDS_START`)
+#[cfg(unix)]
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A socket accepting connections
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// A connection accepted from a `Listener`
+#[derive(Debug)]
+pub enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    /// A TLS session over another connection
+    ///
+    /// Reading and writing both need the session state, so it is behind a lock. A connection is
+    /// only used by one thread at a time, so the lock is never contended.
+    #[cfg(feature = "tls")]
+    Tls(Box<Mutex<rustls::StreamOwned<rustls::ServerConnection, Connection>>>),
+}
+
+impl Listener {
+    /// Listen on a TCP address
+    pub fn bind_tcp(addr: SocketAddr) -> io::Result<Listener> {
+        Ok(Listener::Tcp(TcpListener::bind(addr)?))
+    }
+
+    /// Listen on a Unix domain socket, optionally setting its permissions
+    ///
+    /// A stale socket left by a previous run is replaced, but not one that is still in use.
+    #[cfg(unix)]
+    pub fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<Listener> {
+        let stale = fs::symlink_metadata(path)
+            .map(|meta| meta.file_type().is_socket())
+            .unwrap_or(false);
+        if stale && UnixStream::connect(path).is_err() {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        if let Some(mode) = mode {
+            // Until now, the umask decided who could connect, which is usually only the owner
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+        Ok(Listener::Unix(listener))
+    }
+
+    /// Sockets passed by systemd socket activation
+    ///
+    /// These are only used if `LISTEN_PID` is this process. The variables are left in the
+    /// environment, since changing it is unsound once other threads exist, and a child process
+    /// ignores them anyway because `LISTEN_PID` is not its own.
+    #[cfg(unix)]
+    pub fn from_systemd() -> io::Result<Vec<Listener>> {
+        let ours = env::var("LISTEN_PID").ok()
+            .and_then(|pid| pid.parse::<u32>().ok())
+            .is_some_and(|pid| pid == std::process::id());
+        let count = env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<RawFd>().ok());
+        let count = match count {
+            Some(count) if ours => count,
+            _ => return Ok(Vec::new()),
+        };
+        (LISTEN_FDS_START..LISTEN_FDS_START + count)
+            .map(|fd| unsafe { Listener::from_raw_fd(fd) })
+            .collect()
+    }
+
+    /// Take ownership of a listening socket of either kind
+    ///
+    /// # Safety
+    /// `fd` must be an open listening socket that nothing else owns.
+    #[cfg(unix)]
+    unsafe fn from_raw_fd(fd: RawFd) -> io::Result<Listener> {
+        let tcp = TcpListener::from_raw_fd(fd);
+        // Only an internet socket has an address that `TcpListener` understands
+        if tcp.local_addr().is_ok() {
+            tcp.set_nonblocking(false)?;
+            return Ok(Listener::Tcp(tcp));
+        }
+        let unix = UnixListener::from_raw_fd(tcp.into_raw_fd());
+        unix.local_addr()?;
+        unix.set_nonblocking(false)?;
+        Ok(Listener::Unix(unix))
+    }
+
+    pub fn accept(&self) -> io::Result<Connection> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Connection::Tcp(stream)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.accept().map(|(stream, _)| Connection::Unix(stream)),
+        }
+    }
+
+    /// The TCP address, if this is a TCP socket
+    pub fn tcp_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix(_) => None,
+        }
+    }
+
+    /// The path of a Unix domain socket
+    #[cfg(unix)]
+    pub fn unix_path(&self) -> Option<PathBuf> {
+        match self {
+            Listener::Unix(listener) => listener.local_addr().ok()?.as_pathname().map(Path::to_path_buf),
+            Listener::Tcp(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{addr}"),
+                Err(_) => write!(f, "tcp socket"),
+            },
+            #[cfg(unix)]
+            Listener::Unix(_) => match self.unix_path() {
+                Some(path) => write!(f, "unix:{}", path.display()),
+                None => write!(f, "unix socket"),
+            },
+        }
+    }
+}
+
+impl Connection {
+    /// Start a TLS session over this connection
+    ///
+    /// The handshake happens on the first read or write, so it is subject to their timeouts.
+    #[cfg(feature = "tls")]
+    pub fn into_tls(self, config: Arc<rustls::ServerConfig>) -> io::Result<Connection> {
+        let session = rustls::ServerConnection::new(config).map_err(io::Error::other)?;
+        Ok(Connection::Tls(Box::new(Mutex::new(rustls::StreamOwned::new(session, self)))))
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.set_read_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.lock().unwrap().sock.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.set_write_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.lock().unwrap().sock.set_write_timeout(timeout),
+        }
+    }
+
+    /// End the connection cleanly, which for TLS means telling the client it is closing
+    pub fn finish(&self) {
+        #[cfg(feature = "tls")]
+        if let Connection::Tls(stream) = self {
+            let mut stream = stream.lock().unwrap();
+            stream.conn.send_close_notify();
+            let _ = stream.flush();
+        }
+    }
+}
+
+// Reading and writing through a shared reference, like `&TcpStream`, lets one connection have a
+// separate reader and writer
+impl Read for &Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => (&*stream).read(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => (&*stream).read(buf),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.lock().unwrap().read(buf),
+        }
+    }
+}
+
+impl Write for &Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => (&*stream).write(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => (&*stream).write(buf),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.lock().unwrap().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => (&*stream).flush(),
+            #[cfg(unix)]
+            Connection::Unix(stream) => (&*stream).flush(),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => stream.lock().unwrap().flush(),
+        }
+    }
+}
+
+// A TLS session owns the connection it runs over
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binds_unix_socket_with_mode() {
+        let dir = crate::test_util::sandbox("listener");
+        let path = dir.join("smd.sock");
+        let listener = Listener::bind_unix(&path, Some(0o600)).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(listener.unix_path().as_deref(), Some(path.as_path()));
+
+        // A leftover socket from a previous run is replaced
+        drop(listener);
+        let listener = Listener::bind_unix(&path, None).unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        let conn = listener.accept().unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        (&conn).read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+}
@@ -1,58 +1,97 @@
 use std::{
-    net::{TcpListener, TcpStream},
-    io::{Write, BufReader}, 
-    sync::Arc,
+    env,
+    io::{self, Write},
 };
 
-
 use simple_markdown_server::{
-    handlers::Handler,
-    request::{self, ReqError},
-    response::IntoBytes, 
-    config::Config,
+    config::{Config, ArgError, ConfigError},
+    server::Server,
+    theme,
 };
 
-use threadpool::ThreadPool;
-
-const N_WORKERS: usize = 4;
-
 fn main() -> std::io::Result<()> {
-    let config = Config::build()
-        .source_env()
-        .build();
+    let config = match Config::load(env::args_os().skip(1)) {
+        Ok(config) => config,
+        Err(ConfigError::Args(e @ (ArgError::Help | ArgError::Version))) => {
+            println!("{e}");
+            return Ok(());
+        },
+        Err(ConfigError::Args(ArgError::ExportTheme(dir))) => {
+            if let Err(e) = theme::export(&dir) {
+                eprintln!("error: could not export the theme: {e}");
+                std::process::exit(1);
+            }
+            eprintln!("Wrote the theme to {0}; use it with --static {0}/static --templates {0}/templates",
+                      dir.display());
+            return Ok(());
+        },
+        Err(e @ ConfigError::Args(_)) => {
+            eprintln!("error: {e}\n\nFor more information, try '--help'.");
+            std::process::exit(2);
+        },
+        Err(e) => {
+            eprintln!("error: {e}");
+            std::process::exit(2);
+        },
+    };
     println!("{config:#?}");
-    let listener = TcpListener::bind(config.addr)?;
-
-
-    let pool = ThreadPool::new(N_WORKERS);
-    // Has to be an Arc to ensure lifetimes
-    let handler: Arc<Handler> = Arc::new(Handler::new(config));
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        let handler = handler.clone();
+    let open = config.open_browser;
+    let base_path = config.base_path.clone();
+    let server = Server::bind(config)?;
+    if let Err(e) = server.watch_vault() {
+        eprintln!("Could not watch the vault, backlinks will not be updated: {e}");
+    }
+    for endpoint in server.endpoints() {
+        eprintln!("Listening on {endpoint}");
+    }
+    let scheme = if server.is_tls() { "https" } else { "http" };
+    match server.local_addr() {
+        Ok(addr) if open => open_browser(&format!("{scheme}://localhost:{}{}/", addr.port(), base_path)),
+        Err(_) if open => eprintln!("Not opening a browser, since there is no TCP address"),
+        _ => (),
+    }
 
-        pool.execute(move || {
-            if let Err(e) =  handle_connection(stream, handler) {
-                eprintln!("{e}");
-            }
-        });
+    let shutdown = server.shutdown_handle();
+    let handled = ctrlc::set_handler(move || {
+        if shutdown.is_requested() {
+            // A second signal means the user does not want to wait
+            eprintln!("Stopping immediately");
+            flush_logs();
+            std::process::exit(130);
+        }
+        eprintln!("Shutting down, waiting for requests in progress (signal again to force)");
+        shutdown.request();
+    });
+    if let Err(e) = handled {
+        eprintln!("Could not handle signals: {e}");
     }
 
+    let unfinished = server.run()?;
+    if unfinished > 0 {
+        eprintln!("Stopped with {unfinished} connections unfinished");
+        flush_logs();
+        std::process::exit(1);
+    }
+    eprintln!("Stopped");
+    flush_logs();
     Ok(())
 }
 
-/// Parses the stream as a request, then hands it off to the request handler
-fn handle_connection(mut stream: TcpStream, handler: Arc<Handler>) -> std::io::Result<()>{
-        let mut buf_reader = BufReader::new(&stream);
-        let req = request::from_bufread(&mut buf_reader);
-        // If the request works, then serve it
-        if let Ok(req) = req {
-            eprintln!("{req:#?}");
-            let resp = handler.handle_request(req)?;
-            let encoded = resp.into_bytes();
-            stream.write_all(&encoded)?;
-        } else if let Err(ReqError::IO(e)) = req {
-            return Err(e);
-        }
-        Ok(())
+fn flush_logs() {
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+}
+
+/// Open a url with the platform's default web browser
+fn open_browser(url: &str) {
+    let opener = if cfg!(target_os = "macos") {
+        std::process::Command::new("open").arg(url).spawn()
+    } else if cfg!(windows) {
+        std::process::Command::new("cmd").args(["/C", "start", "", url]).spawn()
+    } else {
+        std::process::Command::new("xdg-open").arg(url).spawn()
+    };
+    if let Err(e) = opener {
+        eprintln!("Could not open a browser: {e}");
+    }
 }
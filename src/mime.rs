@@ -0,0 +1,214 @@
+//! Content-Type detection
+//!
+//! Determines the `Content-Type` of served files. The lookup order is
+//!
+//! 1. custom extension mappings from the config,
+//! 2. the built-in extension table,
+//! 3. sniffing the first bytes of the file for a known signature,
+//! 4. `text/plain` for anything that looks like utf-8 text, and otherwise
+//!    `application/octet-stream`.
+
+use std::{
+    collections::HashMap,
+    path::Path,
+};
+
+use crate::config::Config;
+
+/// Content type of rendered markdown and directory pages
+pub const HTML: &str = "text/html; charset=utf-8";
+/// Content type of the directory tree json
+pub const JSON: &str = "application/json";
+/// Content type for plain text
+pub const TEXT: &str = "text/plain; charset=utf-8";
+/// Content type when nothing better is known
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+/// Number of bytes from the start of a file that are needed for sniffing
+pub const SNIFF_LEN: usize = 512;
+
+/// Extension to MIME type lookup, including any custom mappings from the config
+#[derive(Debug, Default)]
+pub struct MimeTypes {
+    custom: HashMap<String, String>,
+}
+
+impl MimeTypes {
+    pub fn new(config: &Config) -> MimeTypes {
+        let custom = config.mime_types.iter()
+            .map(|(ext, mime)| (normalize_ext(ext), mime.clone()))
+            .collect();
+        MimeTypes { custom }
+    }
+
+    /// Look up the MIME type for a file extension (without the leading `.`)
+    pub fn from_extension(&self, ext: &str) -> Option<&str> {
+        let ext = normalize_ext(ext);
+        if let Some(mime) = self.custom.get(&ext) {
+            return Some(mime.as_str());
+        }
+        builtin(&ext)
+    }
+
+    /// Guess the MIME type of a file from its name, or failing that, its first bytes
+    pub fn guess(&self, path: &Path, head: &[u8]) -> &str {
+        let by_ext = path.extension()
+            .and_then(|e| e.to_str())
+            .and_then(|e| self.from_extension(e));
+        if let Some(mime) = by_ext {
+            return mime;
+        }
+        sniff(head).unwrap_or(OCTET_STREAM)
+    }
+}
+
+fn normalize_ext(ext: &str) -> String {
+    ext.trim_start_matches('.').to_ascii_lowercase()
+}
+
+/// The built-in extension table
+fn builtin(ext: &str) -> Option<&'static str> {
+    let mime = match ext {
+        // Text
+        "html" | "htm" => HTML,
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => JSON,
+        "txt" | "text" | "log" => TEXT,
+        "md" | "markdown" => "text/markdown; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "yaml" | "yml" => "application/yaml",
+        "toml" => "application/toml",
+        "tex" => "application/x-tex",
+        "bib" => "application/x-bibtex",
+        // Images
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "bmp" => "image/bmp",
+        "avif" => "image/avif",
+        "tif" | "tiff" => "image/tiff",
+        // Audio and video
+        "mp3" => "audio/mpeg",
+        "ogg" | "oga" => "audio/ogg",
+        "wav" => "audio/wav",
+        "flac" => "audio/flac",
+        "m4a" => "audio/mp4",
+        "mp4" | "m4v" => "video/mp4",
+        "webm" => "video/webm",
+        "ogv" => "video/ogg",
+        "mov" => "video/quicktime",
+        "mkv" => "video/x-matroska",
+        // Fonts
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        // Documents and archives
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "epub" => "application/epub+zip",
+        "wasm" => "application/wasm",
+        _ => return None,
+    };
+    Some(mime)
+}
+
+/// Whether a custom MIME type can be sent as a `Content-Type` header, like `text/org`
+pub fn is_valid(mime: &str) -> bool {
+    mime.contains('/') && http::HeaderValue::from_str(mime).is_ok()
+}
+
+/// Determine the MIME type from the first bytes of a file
+///
+/// Only a handful of common signatures are recognized. Anything else that is valid utf-8 without
+/// control characters is treated as plain text, even if it looks like html.
+pub fn sniff(head: &[u8]) -> Option<&'static str> {
+    let head = &head[..head.len().min(SNIFF_LEN)];
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"OggS", "audio/ogg"),
+        (b"ID3", "audio/mpeg"),
+        (b"fLaC", "audio/flac"),
+        (b"\x1a\x45\xdf\xa3", "video/webm"),
+        (b"wOFF", "font/woff"),
+        (b"wOF2", "font/woff2"),
+        (b"\0asm", "application/wasm"),
+    ];
+    for (signature, mime) in SIGNATURES {
+        if head.starts_with(signature) {
+            return Some(mime);
+        }
+    }
+    // RIFF containers have their type at an offset
+    if head.len() >= 12 && head.starts_with(b"RIFF") {
+        match &head[8..12] {
+            b"WEBP" => return Some("image/webp"),
+            b"WAVE" => return Some("audio/wav"),
+            _ => (),
+        }
+    }
+    if head.len() >= 8 && &head[4..8] == b"ftyp" {
+        return Some("video/mp4");
+    }
+    // Text formats
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        // The head may cut a multi-byte character in half
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&head[..e.valid_up_to()]).unwrap(),
+        Err(_) => return None,
+    };
+    if text.chars().any(|c| c.is_control() && !c.is_whitespace()) {
+        return None;
+    }
+    // Never html, svg or xml, which could run scripts on the site
+    Some(TEXT)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::*;
+
+    #[test]
+    fn guesses_from_extension() {
+        let types = MimeTypes::default();
+        assert_eq!(types.guess(&PathBuf::from("main.js"), b""), "text/javascript; charset=utf-8");
+        assert_eq!(types.guess(&PathBuf::from("Paper.PDF"), b""), "application/pdf");
+    }
+
+    #[test]
+    fn custom_mapping_overrides_builtin() {
+        let config = Config::build()
+            .add_mime_type(".JS", "application/x-custom")
+            .add_mime_type("org", "text/org")
+            .build();
+        let types = MimeTypes::new(&config);
+        assert_eq!(types.from_extension("js"), Some("application/x-custom"));
+        assert_eq!(types.from_extension("org"), Some("text/org"));
+    }
+
+    #[test]
+    fn sniffs_without_extension() {
+        let types = MimeTypes::default();
+        let path = PathBuf::from("image");
+        assert_eq!(types.guess(&path, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), "image/png");
+        assert_eq!(types.guess(&path, b"RIFF\0\0\0\0WEBPVP8 "), "image/webp");
+        assert_eq!(types.guess(&path, b"  <svg xmlns=\"\">"), TEXT);
+        assert_eq!(types.guess(&path, b"<!DOCTYPE html><script>"), TEXT);
+        assert_eq!(types.guess(&path, b"just some notes\n"), TEXT);
+        assert_eq!(types.guess(&path, b"\0\x01\x02\x03"), OCTET_STREAM);
+    }
+}
@@ -1,73 +1,377 @@
 //! Submodule for parsing and managing HTTP requests
 //!
 
-use std::{string, io::BufRead};
+use std::io::{BufRead, Read};
+
+use http::{
+    HeaderMap, HeaderName, HeaderValue, StatusCode, Version,
+    header::{CONNECTION, CONTENT_LENGTH, EXPECT, TRANSFER_ENCODING},
+};
 
 const MAX_HEADERS: usize = 100;
+/// Longest line giving the size of a chunk, which leaves plenty of room for extensions
+const MAX_CHUNK_LINE: usize = 1024;
+const DEFAULT_MAX_HEADER_SIZE: usize = 16 * 1024;
+const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
 
+/// Limits on the size of a request, so a client cannot make the server buffer without bound
+///
+/// - `header_size` the number of bytes in the request line and headers together
+/// - `body_size` the number of bytes in the body
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub header_size: usize,
+    pub body_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits { header_size: DEFAULT_MAX_HEADER_SIZE, body_size: DEFAULT_MAX_BODY_SIZE }
+    }
+}
+
+/// Read a single request from the reader, with the default `Limits`
 pub fn from_bufread(buf_reader: &mut impl BufRead) 
-        -> Result<http::Request<String>, ReqError> {
-    let mut buf: String = String::new();
-    // stream.read_to_end(&mut buf)?;
-    let mut request = loop {
-        buf_reader.read_line(&mut buf)?;
-        let r = parse_headers(&buf.as_bytes());
-        match r {
+        -> Result<http::Request<Vec<u8>>, ReqError> {
+    from_bufread_limited(buf_reader, &Limits::default())
+}
+
+/// Read a single request from the reader
+///
+/// Only the bytes belonging to this request are consumed, so any pipelined requests are left in
+/// the reader for the next call. This does not answer `Expect: 100-continue`, so a server should
+/// use `read_head` and `read_body` separately.
+///
+/// # Errors
+///   - As in `read_head` and `read_body`
+pub fn from_bufread_limited(buf_reader: &mut impl BufRead, limits: &Limits)
+        -> Result<http::Request<Vec<u8>>, ReqError> {
+    let request = read_head(buf_reader, limits)?;
+    read_body(buf_reader, request, limits)
+}
+
+/// Read the request line and headers, leaving the reader at the start of the body
+///
+/// # Errors
+///   - If the connection closed before a new request started, `ReqError::Closed`
+///   - If the headers are longer than the limit, `ReqError::HeadersTooLarge`
+///   - Otherwise, as in `parse_headers`
+pub fn read_head(buf_reader: &mut impl BufRead, limits: &Limits)
+        -> Result<http::Request<Vec<u8>>, ReqError> {
+    let mut buf: Vec<u8> = Vec::new();
+    loop {
+        let read_len = read_line(buf_reader, &mut buf, limits.header_size)?;
+        if read_len == 0 {
+            return Err(if buf.is_empty() {
+                ReqError::Closed
+            } else {
+                ReqError::IO(std::io::ErrorKind::UnexpectedEof.into())
+            });
+        }
+        // Empty lines before the request line should be ignored (RFC 9112, section 2.2)
+        if buf == b"\r\n" || buf == b"\n" {
+            buf.clear();
+            continue;
+        }
+        match parse_headers(&buf) {
             Err(ReqError::Incomplete) => continue,
-            _ => break r,
+            r => return r,
         };
-    }?;
-    // At this point, the buffer should be at start of body
-    // Now read the body if there is one
-    if let Some(clength) = request.headers().get("content-length") {
-        // The `.to_vec()` performs the memory copd
-        let clength: usize = clength.to_str().unwrap().parse().unwrap();
-        // let buf_reader = buf_reader.take(clength as u64);
-        let mut body_buf: Vec<u8> = vec![0; clength];
-        let _read_len = buf_reader.read(&mut body_buf)?;
-        // eprintln!("Content length: {}\nRead length: {}\nBuffer length: {}",
-        //          clength, read_len, body_buf.len());
-
-        *(request.body_mut()) = String::from_utf8(body_buf)?;
+    }
+}
+
+/// How the length of a request body is determined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// There is no body
+    Empty,
+    /// The body is exactly this many bytes
+    Length(usize),
+    /// The body is sent in chunks, ending with an empty chunk and optional trailers
+    Chunked,
+}
+
+/// Determine how the body of a request is framed, checking it against the limits
+///
+/// # Errors
+///   - If `Transfer-Encoding` is anything but `chunked`, `ReqError::UnsupportedEncoding`
+///   - If both `Transfer-Encoding` and `Content-Length` are present, or `Content-Length` is
+///     malformed, `ReqError::BadLength`
+///   - If `Content-Length` is longer than the limit, `ReqError::BodyTooLarge`
+pub fn framing<T>(req: &http::Request<T>, limits: &Limits) -> Result<Framing, ReqError> {
+    let length = content_length(req)?;
+    if req.headers().contains_key(TRANSFER_ENCODING) {
+        // A request with both could be read differently by a proxy, to smuggle another request
+        if length.is_some() {
+            return Err(ReqError::BadLength);
+        }
+        let codings: Vec<String> = req.headers().get_all(TRANSFER_ENCODING).iter()
+            .map(|v| v.to_str().map_err(|_| ReqError::UnsupportedEncoding))
+            .collect::<Result<Vec<&str>, ReqError>>()?
+            .iter()
+            .flat_map(|v| v.split(','))
+            .map(|c| c.trim().to_ascii_lowercase())
+            .filter(|c| !c.is_empty())
+            .collect();
+        if codings != ["chunked"] {
+            return Err(ReqError::UnsupportedEncoding);
+        }
+        return Ok(Framing::Chunked);
+    }
+    match length {
+        Some(0) | None => Ok(Framing::Empty),
+        Some(length) if length > limits.body_size => Err(ReqError::BodyTooLarge),
+        Some(length) => Ok(Framing::Length(length)),
+    }
+}
+
+/// Whether the client is waiting for `100 Continue` before it sends the body
+///
+/// # Errors
+///   - If the client expects anything else, `ReqError::ExpectationFailed`
+pub fn expects_continue<T>(req: &http::Request<T>) -> Result<bool, ReqError> {
+    let expect = match req.headers().get(EXPECT) {
+        Some(expect) => expect,
+        None => return Ok(false),
+    };
+    if !expect.as_bytes().eq_ignore_ascii_case(b"100-continue") {
+        return Err(ReqError::ExpectationFailed);
+    }
+    // HTTP/1.0 clients do not understand interim responses
+    Ok(req.version() >= Version::HTTP_11)
+}
+
+/// Read the body of a request whose head was read with `read_head`
+///
+/// Trailers of a chunked body are put in the request's extensions as `Trailers`.
+///
+/// # Errors
+///   - As in `framing`
+///   - If a chunked body is malformed, `ReqError::BadChunk`
+///   - If a chunked body or its trailers are longer than the limits, `ReqError::BodyTooLarge` or
+///     `ReqError::HeadersTooLarge`
+pub fn read_body(buf_reader: &mut impl BufRead, mut request: http::Request<Vec<u8>>, limits: &Limits)
+        -> Result<http::Request<Vec<u8>>, ReqError> {
+    match framing(&request, limits)? {
+        Framing::Empty => (),
+        Framing::Length(length) => {
+            let mut body_buf: Vec<u8> = vec![0; length];
+            // Read exactly the body, so that a pipelined request is not consumed
+            buf_reader.read_exact(&mut body_buf)?;
+            *(request.body_mut()) = body_buf;
+        },
+        Framing::Chunked => {
+            let (body, trailers) = read_chunked(buf_reader, limits)?;
+            *(request.body_mut()) = body;
+            request.extensions_mut().insert(Trailers(trailers));
+        },
     }
     Ok(request)
 }
 
-/// Parse a buffer into an `http::Request<String>`
+/// Header fields sent after a chunked body
+#[derive(Debug, Clone, Default)]
+pub struct Trailers(pub HeaderMap);
+
+/// Decode a chunked body (RFC 9112, section 7.1), returning the body and its trailers
+fn read_chunked(buf_reader: &mut impl BufRead, limits: &Limits) -> Result<(Vec<u8>, HeaderMap), ReqError> {
+    let mut body = Vec::new();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if read_line(buf_reader, &mut line, MAX_CHUNK_LINE).map_err(too_long_is(ReqError::BadChunk))? == 0 {
+            return Err(ReqError::IO(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        // Chunk extensions after `;` are ignored
+        let size = line.split(|b| *b == b';').next().unwrap_or_default();
+        let size = std::str::from_utf8(size).map_err(|_| ReqError::BadChunk)?.trim();
+        if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ReqError::BadChunk);
+        }
+        let size = usize::from_str_radix(size, 16).unwrap_or(usize::MAX);
+        if size == 0 {
+            break;
+        }
+        if size > limits.body_size.saturating_sub(body.len()) {
+            return Err(ReqError::BodyTooLarge);
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        buf_reader.read_exact(&mut body[start..])?;
+        line.clear();
+        read_line(buf_reader, &mut line, 2).map_err(too_long_is(ReqError::BadChunk))?;
+        if line != b"\r\n" && line != b"\n" {
+            return Err(ReqError::BadChunk);
+        }
+    }
+    // The trailer section ends with an empty line, just like the headers
+    let mut trailer_buf = Vec::new();
+    loop {
+        let start = trailer_buf.len();
+        if read_line(buf_reader, &mut trailer_buf, limits.header_size)? == 0 {
+            return Err(ReqError::IO(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        if matches!(&trailer_buf[start..], b"\r\n" | b"\n") {
+            break;
+        }
+    }
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let trailers = match httparse::parse_headers(&trailer_buf, &mut headers)? {
+        httparse::Status::Complete((_, headers)) => headers,
+        httparse::Status::Partial => return Err(ReqError::BadChunk),
+    };
+    let mut map = HeaderMap::new();
+    for trailer in trailers {
+        let name = HeaderName::from_bytes(trailer.name.as_bytes()).map_err(|_| ReqError::BadChunk)?;
+        let value = HeaderValue::from_bytes(trailer.value).map_err(|_| ReqError::BadChunk)?;
+        map.append(name, value);
+    }
+    Ok((body, map))
+}
+
+/// Append a line (including its `\n`) to `buf`, returning the number of bytes read
+///
+/// At most one byte past `limit` is read, however long the line is. If `buf` no longer fits in
+/// `limit` bytes, the error is `ReqError::HeadersTooLarge`.
+fn read_line(buf_reader: &mut impl BufRead, buf: &mut Vec<u8>, limit: usize) -> Result<usize, ReqError> {
+    let allowed = (limit + 1).saturating_sub(buf.len()) as u64;
+    let read_len = (&mut *buf_reader).take(allowed).read_until(b'\n', buf)?;
+    if buf.len() > limit {
+        return Err(ReqError::HeadersTooLarge);
+    }
+    Ok(read_len)
+}
+
+/// Replace a `HeadersTooLarge` error, for lines that are not headers
+fn too_long_is(replacement: ReqError) -> impl FnOnce(ReqError) -> ReqError {
+    move |e| match e {
+        ReqError::HeadersTooLarge => replacement,
+        e => e,
+    }
+}
+
+/// The length of the body from the `Content-Length` header, if there is one
+///
+/// The value must be a plain decimal number, and repeated headers must agree (RFC 9110,
+/// section 8.6), since a disagreement could be used to smuggle requests.
+fn content_length<T>(req: &http::Request<T>) -> Result<Option<usize>, ReqError> {
+    let mut length = None;
+    for value in req.headers().get_all(CONTENT_LENGTH) {
+        for value in value.to_str().map_err(|_| ReqError::BadLength)?.split(',') {
+            let value = value.trim();
+            if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(ReqError::BadLength);
+            }
+            // Too large for memory is certainly too large for the limit
+            let parsed = value.parse().unwrap_or(usize::MAX);
+            if length.is_some_and(|l| l != parsed) {
+                return Err(ReqError::BadLength);
+            }
+            length = Some(parsed);
+        }
+    }
+    Ok(length)
+}
+
+/// Parse a buffer into an `http::Request` with an empty body
 ///
 /// # Errors
 ///   - If the request is incomplete, `ReqError::Incomplete`
+///   - If the HTTP version is well-formed, but not 1.0 or 1.1, `ReqError::UnsupportedVersion`
 ///   - If the parser fails, `ReqError::Parse(httparse::Error)`
-///   - If the body is not UTF8, `ReqError::Encoding(string::FromUTF8Error)`
 ///   - If the converting to an `http::Request` fails,  `ReqError::Convert(http::Error)`
-pub fn parse_headers(buf: &[u8]) -> Result<http::Request<String>, ReqError>  {
+#[allow(clippy::needless_borrow, clippy::redundant_closure)]
+pub fn parse_headers(buf: &[u8]) -> Result<http::Request<Vec<u8>>, ReqError>  {
     let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
     let mut preq = httparse::Request::new(&mut headers);
     
-    let result = preq.parse(&buf)?;
+    let result = match preq.parse(&buf) {
+        Err(httparse::Error::Version) if is_http_version(buf) => return Err(ReqError::UnsupportedVersion),
+        r => r?,
+    };
     // eprintln!("parse result: {:?}", result);
     if let httparse::Status::Complete(body_start) = result {
         assert!(buf.len() == body_start, 
                 "Header should end with \"\\r\\n\\r\\n\"");
+        let version = match preq.version {
+            Some(0) => Version::HTTP_10,
+            _ => Version::HTTP_11,
+        };
         let request: http::request::Builder = http::Request::builder()
             .method(preq.method.unwrap())
-            .uri(preq.path.unwrap());
+            .uri(preq.path.unwrap())
+            .version(version);
         let request = preq.headers.iter()
             .fold(request, |r, h| r.header(h.name, h.value));
 
-        return request.body(String::new())
+        return request.body(Vec::new())
             .map_err(|e| ReqError::Convert(e))
     }
     Err(ReqError::Incomplete)
 }
 
+/// Whether the request line is well-formed, with a version like `HTTP/2.0` that is unsupported
+fn is_http_version(buf: &[u8]) -> bool {
+    let line = buf.split(|b| *b == b'\n').next().unwrap_or_default();
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let parts: Vec<&[u8]> = line.split(|b| *b == b' ').collect();
+    let version = match parts.as_slice() {
+        [_method, _target, version] => *version,
+        _ => return false,
+    };
+    matches!(version, [b'H', b'T', b'T', b'P', b'/', major, b'.', minor]
+             if major.is_ascii_digit() && minor.is_ascii_digit())
+}
+
+/// Whether the client wants the connection kept open after this request
+///
+/// HTTP/1.1 connections are persistent unless the client sends `Connection: close`, while HTTP/1.0
+/// connections are only kept open with an explicit `Connection: keep-alive`.
+pub fn keep_alive<T>(req: &http::Request<T>) -> bool {
+    let has_token = |token: &str| req.headers().get_all(CONNECTION).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(token));
+    match req.version() {
+        Version::HTTP_09 | Version::HTTP_10 => has_token("keep-alive"),
+        _ => !has_token("close"),
+    }
+}
+
 #[derive(Debug)]
 pub enum ReqError {
     Incomplete,
+    Closed,
     IO(std::io::Error),
     Parse(httparse::Error),
     Convert(http::Error),
-    Encoding(string::FromUtf8Error),
+    HeadersTooLarge,
+    BodyTooLarge,
+    BadLength,
+    BadChunk,
+    UnsupportedVersion,
+    UnsupportedEncoding,
+    ExpectationFailed,
+}
+
+impl ReqError {
+    /// The status of the response to send for this error
+    ///
+    /// `None` means no response can be sent, because the client has gone or stalled, and the
+    /// connection should just be closed.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            ReqError::Incomplete | ReqError::Closed | ReqError::IO(_) => None,
+            ReqError::UnsupportedVersion => Some(StatusCode::HTTP_VERSION_NOT_SUPPORTED),
+            ReqError::Parse(httparse::Error::TooManyHeaders) | ReqError::HeadersTooLarge =>
+                Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+            ReqError::BodyTooLarge => Some(StatusCode::PAYLOAD_TOO_LARGE),
+            ReqError::UnsupportedEncoding => Some(StatusCode::NOT_IMPLEMENTED),
+            ReqError::ExpectationFailed => Some(StatusCode::EXPECTATION_FAILED),
+            ReqError::Parse(_) | ReqError::Convert(_) | ReqError::BadLength | ReqError::BadChunk =>
+                Some(StatusCode::BAD_REQUEST),
+        }
+    }
 }
 
 impl From<std::io::Error> for ReqError {
@@ -86,8 +390,73 @@ impl From<http::Error> for ReqError  {
         ReqError::Convert(value)
     }
 }
-impl From<string::FromUtf8Error> for ReqError  {
-    fn from(value: string::FromUtf8Error) -> Self {
-        ReqError::Encoding(value)
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+    use super::*;
+
+    #[test]
+    fn reads_pipelined_requests() {
+        let raw = "POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+                   GET /b HTTP/1.1\r\nHost: x\r\n\r\n";
+        let mut reader = BufReader::new(raw.as_bytes());
+        let first = from_bufread(&mut reader).unwrap();
+        assert_eq!(first.uri(), "/a");
+        assert_eq!(first.body(), b"hello");
+        let second = from_bufread(&mut reader).unwrap();
+        assert_eq!(second.uri(), "/b");
+        assert!(matches!(from_bufread(&mut reader), Err(ReqError::Closed)));
+    }
+
+    #[test]
+    fn decodes_chunked_bodies() {
+        let raw: &[u8] = b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                           3;name=value\r\n\xff\x00\xfe\r\nA\r\n0123456789\r\n0\r\n\
+                           Checksum: abc\r\n\r\nGET /b HTTP/1.1\r\n\r\n";
+        let mut reader = BufReader::new(raw);
+        let req = from_bufread(&mut reader).unwrap();
+        assert_eq!(req.body(), b"\xff\x00\xfe0123456789");
+        assert_eq!(req.extensions().get::<Trailers>().unwrap().0.get("checksum").unwrap(), "abc");
+        assert_eq!(from_bufread(&mut reader).unwrap().uri(), "/b");
+
+        let limits = Limits { header_size: 1024, body_size: 8 };
+        let status = |raw: &[u8]| from_bufread_limited(&mut BufReader::new(raw), &limits)
+            .unwrap_err().status();
+        let head = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert_eq!(status(format!("{head}5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n").as_bytes()),
+                   Some(StatusCode::PAYLOAD_TOO_LARGE));
+        assert_eq!(status(format!("{head}5\r\nhello world\r\n0\r\n\r\n").as_bytes()),
+                   Some(StatusCode::BAD_REQUEST));
+        assert_eq!(status(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n"),
+                   Some(StatusCode::NOT_IMPLEMENTED));
+        assert_eq!(status(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n"),
+                   Some(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn errors_map_to_statuses() {
+        let limits = Limits { header_size: 64, body_size: 4 };
+        let status = |raw: &str| from_bufread_limited(&mut BufReader::new(raw.as_bytes()), &limits)
+            .unwrap_err().status();
+        assert_eq!(status("GET / HTTP/1.1\r\nX-Long: 0123456789012345678901234567890123456789\r\n\r\n"),
+                   Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE));
+        assert_eq!(status("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello"),
+                   Some(StatusCode::PAYLOAD_TOO_LARGE));
+        assert_eq!(status("POST / HTTP/1.1\r\nContent-Length: +3\r\n\r\nabc"), Some(StatusCode::BAD_REQUEST));
+        assert_eq!(status("POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 2\r\n\r\nabc"),
+                   Some(StatusCode::BAD_REQUEST));
+        assert_eq!(status("GET / HTTP/2.0\r\n\r\n"), Some(StatusCode::HTTP_VERSION_NOT_SUPPORTED));
+        assert_eq!(status("GET /a b HTTP/1.1\r\n\r\n"), Some(StatusCode::BAD_REQUEST));
+        assert_eq!(status(""), None);
+    }
+
+    #[test]
+    fn keep_alive_follows_version_defaults() {
+        let parse = |raw: &str| from_bufread(&mut BufReader::new(raw.as_bytes())).unwrap();
+        assert!(keep_alive(&parse("GET / HTTP/1.1\r\n\r\n")));
+        assert!(!keep_alive(&parse("GET / HTTP/1.1\r\nConnection: Close\r\n\r\n")));
+        assert!(!keep_alive(&parse("GET / HTTP/1.0\r\n\r\n")));
+        assert!(keep_alive(&parse("GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n")));
     }
 }
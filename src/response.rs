@@ -4,8 +4,19 @@
 use http::{
     HeaderName, 
     HeaderValue, 
+    StatusCode,
+    Version,
+    header::{ACCEPT_RANGES, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING},
     response::Parts};
 
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    time::Duration,
+};
+
+use crate::mime;
+
 // Export http::Response, because it's used so much
 pub use http::Response;
 
@@ -21,6 +32,7 @@ pub trait IntoBytes {
 // IntoBytes implementations {{{
 
 impl IntoBytes for Response<String> {
+    #[allow(clippy::needless_return)]
     fn into_bytes(self) -> Vec<u8> {
         let (parts, body) = self.into_parts();
         let h = encode_header(parts);
@@ -29,8 +41,13 @@ impl IntoBytes for Response<String> {
 }
 
 impl IntoBytes for Response<Vec<u8>> {
+    #[allow(clippy::needless_return)]
     fn into_bytes(self) -> Vec<u8> {
-        let (parts, body) = self.into_parts();
+        let (mut parts, body) = self.into_parts();
+        // Persistent connections need every body to be delimited
+        if !parts.headers.contains_key(CONTENT_LENGTH) {
+            parts.headers.insert(CONTENT_LENGTH, body.len().into());
+        }
         let h = encode_header(parts);
         return [h, b"\r\n".to_vec(), body].concat();
     }
@@ -45,65 +62,295 @@ impl IntoBytes for Response<()> {
 
 // }}}
 
+// Response bodies {{{
+
+/// Size of the chunks used when streaming a body of unknown length
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// The body of a response
+///
+/// Bodies are written out incrementally by `write_response`, so large files never have to be
+/// held in memory.
+///
+/// - `Bytes` an in-memory body, for rendered pages and small responses
+/// - `File` `len` bytes of an open file, starting at `offset`
+/// - `Parts` several bodies sent one after another, as in `multipart/byteranges`
+/// - `Stream` a reader of unknown length, sent with chunked transfer encoding
+pub enum Body {
+    Bytes(Vec<u8>),
+    File { file: File, offset: u64, len: u64 },
+    Parts(Vec<Body>),
+    Stream(Box<dyn Read + Send>),
+}
+
+impl Body {
+    pub fn empty() -> Body {
+        Body::Bytes(Vec::new())
+    }
+
+    /// The length of the body, if it is known before sending
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Bytes(b) => Some(b.len() as u64),
+            Body::File { len, .. } => Some(*len),
+            Body::Parts(parts) => parts.iter().map(Body::len).sum(),
+            Body::Stream(_) => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    /// Write the body, using chunked encoding for streams if `chunked` is set
+    fn write_to(self, w: &mut impl Write, chunked: bool) -> io::Result<()> {
+        match self {
+            Body::Bytes(b) => w.write_all(&b),
+            Body::File { mut file, offset, len } => {
+                file.seek(SeekFrom::Start(offset))?;
+                let copied = io::copy(&mut file.take(len), w)?;
+                if copied < len {
+                    // The file shrunk after the headers were sent
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                Ok(())
+            },
+            Body::Parts(parts) => {
+                parts.into_iter().try_for_each(|part| part.write_to(w, chunked))
+            },
+            Body::Stream(mut reader) if chunked => {
+                let mut buf = vec![0; CHUNK_SIZE];
+                loop {
+                    let n = match reader.read(&mut buf) {
+                        Ok(0) => break,
+                        Ok(n) => n,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
+                    };
+                    write!(w, "{n:X}\r\n")?;
+                    w.write_all(&buf[..n])?;
+                    w.write_all(b"\r\n")?;
+                }
+                w.write_all(b"0\r\n\r\n")
+            },
+            Body::Stream(mut reader) => io::copy(&mut reader, w).map(|_| ()),
+        }
+    }
+}
+
+impl std::fmt::Debug for Body {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Body::Bytes(b) => write!(f, "Bytes({} bytes)", b.len()),
+            Body::File { offset, len, .. } => write!(f, "File({len} bytes from {offset})"),
+            Body::Parts(parts) => f.debug_tuple("Parts").field(parts).finish(),
+            Body::Stream(_) => write!(f, "Stream"),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(value: Vec<u8>) -> Self {
+        Body::Bytes(value)
+    }
+}
+
+impl From<String> for Body {
+    fn from(value: String) -> Self {
+        Body::Bytes(value.into_bytes())
+    }
+}
+
+/// Set the headers that delimit the body, unless they were already set
+///
+/// Bodies of known length get a `Content-Length`, and streams use chunked transfer encoding.
+/// This must be done before the body is dropped for a HEAD request.
+pub fn set_framing(resp: &mut Response<Body>) {
+    let headers = resp.headers();
+    if headers.contains_key(CONTENT_LENGTH) || headers.contains_key(TRANSFER_ENCODING) {
+        return;
+    }
+    // These responses never have a body (RFC 9110, section 8.6)
+    let status = resp.status();
+    if status.is_informational() || status == StatusCode::NO_CONTENT || status == StatusCode::NOT_MODIFIED {
+        return;
+    }
+    match resp.body().len() {
+        Some(len) => { resp.headers_mut().insert(CONTENT_LENGTH, len.into()); },
+        None => { resp.headers_mut().insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked")); },
+    }
+}
+
+/// Write a response to the stream
+///
+/// HTTP/1.0 clients don't understand chunked encoding, so a stream of unknown length is sent
+/// as-is, and the connection must be closed afterwards to mark its end.
+pub fn write_response(mut resp: Response<Body>, w: &mut impl Write, version: Version) -> io::Result<()> {
+    let chunked = version >= Version::HTTP_11;
+    if chunked || resp.body().len().is_some() {
+        set_framing(&mut resp);
+    }
+    let (parts, body) = resp.into_parts();
+    w.write_all(&encode_header(parts))?;
+    w.write_all(b"\r\n")?;
+    body.write_to(w, chunked)?;
+    w.flush()
+}
+
+// }}}
+
 // General responses {{{
 
 /// Create an "unimplemented" response for unimplemented requests
-pub fn unimplemented() -> Response<Vec<u8>> {
+pub fn unimplemented() -> Response<Body> {
     Response::builder()
         .status(501)
-        .body(Vec::new())
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Permanently redirect to `location`
+pub fn moved_permanently(location: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::MOVED_PERMANENTLY)
+        .header(http::header::LOCATION, location)
+        .body(Body::empty())
+        .unwrap_or_else(|_| server_error())
+}
+
+/// Temporarily redirect to `location`
+pub fn found(location: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::FOUND)
+        .header(http::header::LOCATION, location)
+        .body(Body::empty())
+        .unwrap_or_else(|_| server_error())
+}
+
+/// A "forbidden" response for resources that may not be served, like paths outside the root
+pub fn forbidden() -> Response<Body> {
+    Response::builder()
+        .status(403)
+        .body(Body::empty())
         .unwrap()
 }
 
 /// A "not allowed" response for recognized, but not allowed for the resource
-pub fn not_allowed() -> Response<Vec<u8>> {
+pub fn not_allowed() -> Response<Body> {
     Response::builder()
         .status(405)
-        .body(Vec::new())
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// A plain error response, with the reason phrase as its body
+pub fn error(status: StatusCode) -> Response<Body> {
+    let reason = status.canonical_reason().unwrap_or_default();
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, mime::TEXT)
+        .body(Body::from(format!("{} {reason}\n", status.as_u16())))
+        .unwrap()
+}
+
+/// When the server is too busy to handle the request
+///
+/// `Retry-After` tells the client when it is worth trying again.
+pub fn service_unavailable(retry_after: Duration) -> Response<Body> {
+    Response::builder()
+        .status(503)
+        .header(http::header::RETRY_AFTER, retry_after.as_secs().max(1))
+        .body(Body::empty())
         .unwrap()
 }
 
 /// If any error occurs on the server side
-pub fn server_error() -> Response<Vec<u8>> {
+pub fn server_error() -> Response<Body> {
     Response::builder()
         .status(500)
-        .body(Vec::new())
+        .body(Body::empty())
         .unwrap()
 }
 
-/// Create a 200 response from a String
+/// Create a 200 `text/plain` response from a String
 ///
 /// Can also be used for other responses, by mutating the status code afterwards, like
 /// ```
-/// use zettel_web::response;
+/// use simple_markdown_server::response;
 /// let mut resp = response::from_string(String::from("Not found"));
 /// *resp.status_mut() = http::StatusCode::NOT_FOUND;
 /// assert_eq!(resp.status(), http::StatusCode::NOT_FOUND);
 /// ```
-pub fn from_string(content: String) -> Response<Vec<u8>> {
+pub fn from_string(content: String) -> Response<Body> {
+    with_content_type(content.into_bytes(), mime::TEXT)
+}
+
+/// Create a 200 `application/octet-stream` response from a Vec<u8>
+pub fn from_bytes(content: Vec<u8>) -> Response<Body> {
+    with_content_type(content, mime::OCTET_STREAM)
+}
+
+/// Create a 200 response for an html page
+pub fn html(content: String) -> Response<Body> {
+    with_content_type(content.into_bytes(), mime::HTML)
+}
+
+/// Create a 200 response for a json document
+pub fn json(content: String) -> Response<Body> {
+    with_content_type(content.into_bytes(), mime::JSON)
+}
+
+/// The header value for a MIME type, falling back on `application/octet-stream` if it is invalid
+fn content_type_value(content_type: &str) -> HeaderValue {
+    HeaderValue::from_str(content_type).unwrap_or(HeaderValue::from_static(mime::OCTET_STREAM))
+}
+
+/// Create a 200 response with an explicit `Content-Type`
+pub fn with_content_type(content: Vec<u8>, content_type: &str) -> Response<Body> {
     Response::builder()
         .status(200)
         .header("Content-Length", content.len())
-        .body(content.into_bytes())
+        .header(CONTENT_TYPE, content_type_value(content_type))
+        .body(Body::from(content))
         .unwrap()
 }
 
-/// Create a 200 response from a Vec<u8>
-pub fn from_bytes(content: Vec<u8>) -> Response<Vec<u8>> {
+/// Create a 200 response that streams the first `len` bytes of a file
+pub fn from_file(file: File, len: u64, content_type: &str) -> Response<Body> {
     Response::builder()
         .status(200)
-        .header("Content-Length", content.len())
-        .body(content)
+        .header(CONTENT_LENGTH, len)
+        .header(CONTENT_TYPE, content_type_value(content_type))
+        .header(ACCEPT_RANGES, "bytes")
+        .body(Body::File { file, offset: 0, len })
         .unwrap()
-    
 }
 
 
+/// Set the `Connection` header for whether the connection persists after this response
+///
+/// For persistent connections, the `Keep-Alive` header advertises the idle timeout and the
+/// number of requests remaining.
+pub fn set_keep_alive<T>(resp: &mut Response<T>, keep_alive: Option<(Duration, usize)>) {
+    let headers = resp.headers_mut();
+    match keep_alive {
+        Some((timeout, remaining)) => {
+            headers.insert(CONNECTION, HeaderValue::from_static("keep-alive"));
+            let value = format!("timeout={}, max={}", timeout.as_secs(), remaining);
+            headers.insert("keep-alive", HeaderValue::from_str(&value).unwrap());
+        },
+        None => {
+            headers.insert(CONNECTION, HeaderValue::from_static("close"));
+        },
+    }
+}
+
 // }}}
 
 // Actual Encoding of responses {{{
 
 /// Encode a response header
+#[allow(clippy::needless_return)]
 fn encode_header(parts: Parts) -> Vec<u8> {
     let mut lines: Vec<Vec<u8>> = Vec::new();
     lines.push(statusline(&parts));
@@ -129,3 +376,33 @@ fn headerline(k: &HeaderName, v: &HeaderValue) -> Vec<u8> {
 }
 
 // }}}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streams_unknown_length_as_chunks() {
+        let resp = Response::builder()
+            .body(Body::Stream(Box::new(&b"hello world"[..])))
+            .unwrap();
+        let mut out = Vec::new();
+        write_response(resp, &mut out, Version::HTTP_11).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("transfer-encoding: chunked\r\n"));
+        assert!(out.ends_with("\r\n\r\nB\r\nhello world\r\n0\r\n\r\n"));
+    }
+
+    #[test]
+    fn framing_survives_dropped_body() {
+        let mut resp = from_string(String::from("some text"));
+        resp.headers_mut().remove(CONTENT_LENGTH);
+        set_framing(&mut resp);
+        *resp.body_mut() = Body::empty();
+        let mut out = Vec::new();
+        write_response(resp, &mut out, Version::HTTP_11).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("content-length: 9\r\n"));
+        assert!(out.ends_with("\r\n\r\n"));
+    }
+}
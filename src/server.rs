@@ -0,0 +1,519 @@
+//! The server itself
+//!
+//! A `Server` owns the listening socket and a pool of workers, and hands each connection to a
+//! `Handler`. It can be run on the current thread (as the binary does), or spawned in the
+//! background and stopped later:
+//!
+//! ```no_run
+//! use simple_markdown_server::{config::Config, server::Server};
+//!
+//! let config = Config::build().set_root("notes").set_address(([127, 0, 0, 1], 0)).build();
+//! let server = Server::bind(config)?.spawn()?;
+//! println!("serving on {}", server.local_addr()?);
+//! server.shutdown()?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use threadpool::ThreadPool;
+
+use crate::{
+    config::Config,
+    handlers::Handler,
+    listener::{Connection, Listener},
+    request::{self, ReqError},
+    response,
+    shutdown::Shutdown,
+};
+
+/// How often waiting threads check whether a shutdown was requested
+const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+
+/// What the connections of a listener are for
+#[derive(Clone)]
+enum Role {
+    /// Serve the site
+    Serve,
+    /// Serve the site over TLS
+    #[cfg(feature = "tls")]
+    ServeTls(Arc<rustls::ServerConfig>),
+    /// Redirect every request to HTTPS on this port
+    Redirect(u16),
+}
+
+impl Role {
+    fn describe(&self) -> String {
+        match self {
+            Role::Serve => String::from("http"),
+            #[cfg(feature = "tls")]
+            Role::ServeTls(_) => String::from("https"),
+            Role::Redirect(port) => format!("http, redirecting to https on port {port}"),
+        }
+    }
+}
+
+/// A server bound to its address, but not yet accepting connections
+pub struct Server {
+    listeners: Vec<(Listener, Role)>,
+    /// Socket file created by the server, to remove when it stops
+    socket_file: Option<PathBuf>,
+    handler: Arc<Handler>,
+    shutdown: Shutdown,
+}
+
+/// A server running on a background thread
+pub struct RunningServer {
+    addr: Option<SocketAddr>,
+    shutdown: Shutdown,
+    thread: JoinHandle<io::Result<usize>>,
+}
+
+impl Server {
+    /// Bind the configured listening sockets
+    ///
+    /// In order of preference, these are the sockets passed by systemd (if `socket_activation`
+    /// is enabled), the Unix domain socket `unix_socket`, or the TCP address `addr`. With port
+    /// `0`, the system chooses a free port, which `local_addr` reports. If TLS is enabled and
+    /// `redirect_port` is set, plain HTTP on that port is redirected to HTTPS.
+    pub fn bind(config: Config) -> io::Result<Server> {
+        #[cfg(feature = "tls")]
+        let role = match crate::tls::server_config(&config)? {
+            Some(tls) => Role::ServeTls(tls),
+            None => Role::Serve,
+        };
+        #[cfg(not(feature = "tls"))]
+        let role = match config.tls_enabled() {
+            true => return Err(io::Error::new(io::ErrorKind::Unsupported, "built without TLS support")),
+            false => Role::Serve,
+        };
+        let mut listeners = Vec::new();
+        let mut socket_file = None;
+        #[cfg(unix)]
+        if config.socket_activation {
+            listeners = Listener::from_systemd()?;
+        }
+        if listeners.is_empty() {
+            match &config.unix_socket {
+                #[cfg(unix)]
+                Some(path) => {
+                    listeners.push(Listener::bind_unix(path, config.socket_mode)?);
+                    socket_file = Some(path.clone());
+                },
+                #[cfg(not(unix))]
+                Some(_) => return Err(io::Error::new(io::ErrorKind::Unsupported,
+                                                     "unix sockets are not supported on this platform")),
+                None => listeners.push(Listener::bind_tcp(config.addr)?),
+            }
+        }
+        let mut listeners: Vec<(Listener, Role)> = listeners.into_iter()
+            .map(|listener| (listener, role.clone()))
+            .collect();
+        if let Some(port) = config.redirect_port {
+            if !config.tls_enabled() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "redirecting to HTTPS needs TLS"));
+            }
+            let https_port = listeners.iter()
+                .find_map(|(listener, _)| listener.tcp_addr())
+                .map_or(443, |addr| addr.port());
+            let redirect = Listener::bind_tcp(SocketAddr::new(config.addr.ip(), port))?;
+            listeners.push((redirect, Role::Redirect(https_port)));
+        }
+        let shutdown = Shutdown::new();
+        for (listener, _) in listeners.iter() {
+            shutdown.watch(listener);
+        }
+        Ok(Server {
+            listeners,
+            socket_file,
+            handler: Arc::new(Handler::new(config)),
+            shutdown,
+        })
+    }
+
+    /// The TCP address the server is listening on
+    ///
+    /// This is an error if the server only listens on Unix domain sockets.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners.iter()
+            .filter(|(_, role)| !matches!(role, Role::Redirect(_)))
+            .find_map(|(listener, _)| listener.tcp_addr())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not listening on a TCP socket"))
+    }
+
+    /// Whether the site is served over TLS
+    pub fn is_tls(&self) -> bool {
+        self.listeners.iter().any(|(_, role)| !matches!(role, Role::Serve | Role::Redirect(_)))
+    }
+
+    /// Descriptions of the sockets the server is listening on, for logging
+    pub fn endpoints(&self) -> Vec<String> {
+        self.listeners.iter()
+            .map(|(listener, role)| format!("{listener} ({})", role.describe()))
+            .collect()
+    }
+
+    /// Index the vault for backlinks before accepting connections, and keep it up to date
+    ///
+    /// See `Handler::watch_links`.
+    pub fn watch_vault(&self) -> io::Result<()> {
+        self.handler.watch_links()
+    }
+
+    /// A handle that stops the server when requested
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Accept connections on the current thread until a shutdown is requested
+    ///
+    /// With several listeners, the others are accepted on threads of their own. Connections in
+    /// progress are then given the configured `shutdown_timeout` to finish.
+    /// Returns the number of connections that did not finish in time.
+    pub fn run(mut self) -> io::Result<usize> {
+        let pool = ThreadPool::new(self.handler.config().workers);
+        let mut listeners = std::mem::take(&mut self.listeners).into_iter();
+        let first = listeners.next();
+        let others: Vec<JoinHandle<()>> = listeners
+            .map(|(listener, role)| {
+                let (pool, handler, shutdown) = (pool.clone(), self.handler.clone(), self.shutdown.clone());
+                thread::Builder::new()
+                    .name(String::from("smd-accept"))
+                    .spawn(move || accept_loop(listener, role, &pool, &handler, &shutdown))
+            })
+            .collect::<io::Result<_>>()?;
+        if let Some((listener, role)) = first {
+            accept_loop(listener, role, &pool, &self.handler, &self.shutdown);
+        }
+        for other in others {
+            let _ = other.join();
+        }
+        if let Some(path) = self.socket_file.take() {
+            let _ = std::fs::remove_file(path);
+        }
+
+        // Give the workers until the deadline to finish what they are doing
+        let deadline = Instant::now() + self.handler.config().shutdown_timeout;
+        while pool.active_count() + pool.queued_count() > 0 && Instant::now() < deadline {
+            thread::sleep(SHUTDOWN_POLL.min(deadline.saturating_duration_since(Instant::now())));
+        }
+        Ok(pool.active_count() + pool.queued_count())
+    }
+
+    /// Run the server on a background thread
+    pub fn spawn(self) -> io::Result<RunningServer> {
+        let addr = self.local_addr().ok();
+        let shutdown = self.shutdown_handle();
+        let thread = thread::Builder::new()
+            .name(String::from("smd-accept"))
+            .spawn(move || self.run())?;
+        Ok(RunningServer { addr, shutdown, thread })
+    }
+}
+
+/// Hand connections from one listener to the pool until a shutdown is requested
+fn accept_loop(listener: Listener, role: Role, pool: &ThreadPool, handler: &Arc<Handler>, shutdown: &Shutdown) {
+    let config = handler.config();
+    loop {
+        let stream = listener.accept();
+        if shutdown.is_requested() {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            // e.g. out of file descriptors, which should not bring down the server
+            Err(e) => {
+                eprintln!("Could not accept connection: {e}");
+                continue;
+            },
+        };
+        if pool.active_count() >= pool.max_count() && pool.queued_count() >= config.max_pending {
+            // A TLS client would not understand a plain response, so it is just closed
+            if matches!(role, Role::Serve | Role::Redirect(_)) {
+                if let Err(e) = turn_away(stream, config) {
+                    eprintln!("{e}");
+                }
+            }
+            continue;
+        }
+        let handler = handler.clone();
+        let shutdown = shutdown.clone();
+        let role = role.clone();
+        let workers = pool.clone();
+
+        pool.execute(move || {
+            let result = match role {
+                Role::Serve => handle_connection(stream, handler, &shutdown, &workers),
+                #[cfg(feature = "tls")]
+                Role::ServeTls(tls) => stream.into_tls(tls)
+                    .and_then(|stream| handle_connection(stream, handler, &shutdown, &workers)),
+                Role::Redirect(https_port) => redirect_connection(stream, &handler, https_port),
+            };
+            if let Err(e) = result {
+                eprintln!("{e}");
+            }
+        });
+    }
+}
+
+impl RunningServer {
+    /// The TCP address the server is listening on, see `Server::local_addr`
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.addr.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not listening on a TCP socket"))
+    }
+
+    /// A handle that stops the server when requested
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Stop the server and wait for it, see `join`
+    pub fn shutdown(self) -> io::Result<usize> {
+        self.shutdown.request();
+        self.join()
+    }
+
+    /// Wait for the server to stop
+    ///
+    /// Returns the number of connections that did not finish before the shutdown timeout.
+    pub fn join(self) -> io::Result<usize> {
+        self.thread.join()
+            .unwrap_or_else(|_| Err(io::Error::other("server thread panicked")))
+    }
+}
+
+/// Answer a connection with `503 Service Unavailable` when every worker is busy
+///
+/// This runs on the accepting thread, so the write must not be allowed to block for long.
+fn turn_away(stream: Connection, config: &Config) -> io::Result<()> {
+    stream.set_write_timeout(Some(Duration::from_secs(1)))?;
+    let mut resp = response::service_unavailable(config.keep_alive_timeout);
+    response::set_keep_alive(&mut resp, None);
+    response::write_response(resp, &mut BufWriter::new(&stream), http::Version::HTTP_11)
+}
+
+/// Answer a single request with a redirect to the same location over HTTPS
+fn redirect_connection(stream: Connection, handler: &Handler, https_port: u16) -> io::Result<()> {
+    let config = handler.config();
+    stream.set_read_timeout(limit(config.read_timeout))?;
+    stream.set_write_timeout(limit(config.write_timeout))?;
+    let limits = request::Limits { header_size: config.max_header_size, body_size: config.max_body_size };
+    let resp = match request::read_head(&mut BufReader::new(&stream), &limits) {
+        Ok(req) => redirect_to_https(&req, https_port),
+        Err(e) => match e.status() {
+            Some(status) => response::error(status),
+            None => return Ok(()),
+        },
+    };
+    let mut resp = resp;
+    response::set_keep_alive(&mut resp, None);
+    response::write_response(resp, &mut BufWriter::new(&stream), http::Version::HTTP_11)
+}
+
+/// `308 Permanent Redirect` to the requested host and path, with the HTTPS port
+fn redirect_to_https<T>(req: &http::Request<T>, https_port: u16) -> http::Response<response::Body> {
+    let host = req.headers().get(http::header::HOST).and_then(|h| h.to_str().ok());
+    // Drop the port, keeping the brackets of an IPv6 address
+    let host = match host {
+        Some(host) if host.starts_with('[') => host.split_inclusive(']').next(),
+        Some(host) => host.split(':').next(),
+        None => None,
+    };
+    let host = match host.filter(|h| !h.is_empty()) {
+        Some(host) => host,
+        None => return response::error(http::StatusCode::BAD_REQUEST),
+    };
+    let authority = match https_port {
+        443 => host.to_string(),
+        port => format!("{host}:{port}"),
+    };
+    let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+    response::Response::builder()
+        .status(http::StatusCode::PERMANENT_REDIRECT)
+        .header(http::header::LOCATION, format!("https://{authority}{path}"))
+        .body(response::Body::empty())
+        .unwrap_or_else(|_| response::error(http::StatusCode::BAD_REQUEST))
+}
+
+/// Parses the stream as requests, and hands them off to the request handler
+///
+/// The connection is kept open for further (possibly pipelined) requests until the client asks
+/// to close it, it sits idle for longer than the keep-alive timeout, or the maximum number of
+/// requests has been served. Once a shutdown is requested, the connection is closed after the
+/// request in progress.
+fn handle_connection(stream: Connection, handler: Arc<Handler>, shutdown: &Shutdown, workers: &ThreadPool) -> std::io::Result<()>{
+        let timeout = handler.config().keep_alive_timeout;
+        let read_timeout = limit(handler.config().read_timeout);
+        let max_requests = handler.config().max_requests;
+        let limits = request::Limits {
+            header_size: handler.config().max_header_size,
+            body_size: handler.config().max_body_size,
+        };
+        stream.set_write_timeout(limit(handler.config().write_timeout))?;
+        // The reader has to persist between requests, since it may hold pipelined requests
+        let mut buf_reader = BufReader::new(&stream);
+        let mut writer = BufWriter::new(&stream);
+        for served in 1..=max_requests {
+            // Wait for the next request with the keep-alive timeout, then read it with the read
+            // timeout, so a slow client cannot hold on to a worker indefinitely
+            if served > 1 && buf_reader.buffer().is_empty()
+                    && !wait_for_request(&stream, &mut buf_reader, timeout, shutdown, workers)? {
+                break;
+            }
+            stream.set_read_timeout(read_timeout)?;
+            let req = match read_request(&mut buf_reader, &mut writer, &limits) {
+                Ok(req) => req,
+                Err(ReqError::Closed) => break,
+                // An idle connection timed out
+                Err(ReqError::IO(e)) if is_timeout(&e) => break,
+                Err(ReqError::IO(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(ReqError::IO(e)) => return Err(e),
+                Err(e) => {
+                    // The rest of the stream cannot be trusted, so the connection is closed
+                    if let Some(status) = e.status() {
+                        let mut resp = response::error(status);
+                        response::set_keep_alive(&mut resp, None);
+                        response::write_response(resp, &mut writer, http::Version::HTTP_11)?;
+                    }
+                    break;
+                },
+            };
+            eprintln!("{} {} {:?}", req.method(), req.uri(), req.version());
+            let version = req.version();
+            let mut keep_alive = request::keep_alive(&req) && served < max_requests
+                && !shutdown.is_requested();
+            let mut resp = handler.handle_request(req)?;
+            // Without chunked encoding, the end of a stream is marked by closing the connection
+            if version < http::Version::HTTP_11 && resp.body().len().is_none() {
+                keep_alive = false;
+            }
+            response::set_keep_alive(&mut resp,
+                keep_alive.then_some((timeout, max_requests - served)));
+            response::write_response(resp, &mut writer, version)?;
+            if !keep_alive {
+                break;
+            }
+        }
+        writer.flush()?;
+        stream.finish();
+        Ok(())
+}
+
+/// Read a request, sending `100 Continue` first if the client is waiting for it
+///
+/// The body is only invited once its length has been checked against the limits.
+fn read_request(reader: &mut impl BufRead, writer: &mut impl Write, limits: &request::Limits)
+        -> Result<http::Request<Vec<u8>>, ReqError> {
+    let head = request::read_head(reader, limits)?;
+    let framing = request::framing(&head, limits)?;
+    if request::expects_continue(&head)? && framing != request::Framing::Empty {
+        writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        writer.flush()?;
+    }
+    request::read_body(reader, head, limits)
+}
+
+/// Wait on an idle connection for the start of another request
+///
+/// Returns `false` if the connection should be closed instead: the client closed it, it timed
+/// out, the server is shutting down, or other connections are waiting for a worker. The wait is
+/// done in slices so those are noticed.
+fn wait_for_request(stream: &Connection, reader: &mut BufReader<&Connection>, timeout: Duration,
+                    shutdown: &Shutdown, workers: &ThreadPool) -> io::Result<bool> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        // An idle connection gives up its worker to a waiting one
+        if remaining.is_zero() || shutdown.is_requested() || workers.queued_count() > 0 {
+            return Ok(false);
+        }
+        stream.set_read_timeout(Some(remaining.min(SHUTDOWN_POLL)))?;
+        match reader.fill_buf() {
+            Ok(buf) => return Ok(!buf.is_empty()),
+            Err(e) if is_timeout(&e) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// A socket timeout, where zero means no timeout
+fn limit(timeout: Duration) -> Option<Duration> {
+    Some(timeout).filter(|t| !t.is_zero())
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Read, net::TcpStream};
+
+    #[test]
+    fn serves_on_chosen_port_until_shutdown() {
+        let config = Config::build()
+            .set_root("src")
+            .set_address(([127, 0, 0, 1], 0))
+            .build();
+        let server = Server::bind(config).unwrap().spawn().unwrap();
+        let addr = server.local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /lib.rs HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        assert!(reply.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(reply.contains("pub mod server;"));
+
+        assert_eq!(server.shutdown().unwrap(), 0);
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn idle_connections_make_way() {
+        let config = Config::build()
+            .set_root("src")
+            .set_address(([127, 0, 0, 1], 0))
+            .set_workers(1)
+            .set_keep_alive_timeout(Duration::from_secs(60))
+            .build();
+        let server = Server::bind(config).unwrap().spawn().unwrap();
+        let addr = server.local_addr().unwrap();
+        let get = |stream: &mut TcpStream| {
+            stream.write_all(b"HEAD /lib.rs HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            let mut reply = [0; 12];
+            stream.read_exact(&mut reply).unwrap();
+            assert_eq!(&reply, b"HTTP/1.1 200");
+        };
+        let mut idle = TcpStream::connect(addr).unwrap();
+        get(&mut idle);
+
+        // The only worker is waiting on the idle connection, until another one needs it
+        let mut other = TcpStream::connect(addr).unwrap();
+        other.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        get(&mut other);
+        drop(other);
+        assert_eq!(server.shutdown().unwrap(), 0);
+    }
+
+    #[test]
+    fn redirects_to_https_port() {
+        let location = |host: &str, port| {
+            let req = http::Request::builder().uri("/notes/a.md?x=1").header("host", host).body(()).unwrap();
+            let resp = redirect_to_https(&req, port);
+            resp.headers().get(http::header::LOCATION).map(|l| l.to_str().unwrap().to_string())
+        };
+        assert_eq!(location("notes.lan:8080", 8443).as_deref(), Some("https://notes.lan:8443/notes/a.md?x=1"));
+        assert_eq!(location("[::1]:80", 443).as_deref(), Some("https://[::1]/notes/a.md?x=1"));
+        assert_eq!(location("", 443), None);
+    }
+}
@@ -0,0 +1,109 @@
+//! Stopping the server
+//!
+//! A `Shutdown` handle is shared between the thread accepting connections, the workers, and
+//! whatever decides that the server should stop (a signal handler, or a test). Once a shutdown
+//! is requested, no new connections are accepted, and workers close their connections after the
+//! request in progress.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::listener::Listener;
+
+/// How to reach a listener, to wake it from `accept`
+#[derive(Debug)]
+enum Waker {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+}
+
+/// A cloneable handle to request, and check for, a shutdown
+#[derive(Clone, Debug, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+    /// Listeners to wake from a blocking `accept`
+    listeners: Arc<Mutex<Vec<Waker>>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    /// Whether a shutdown has been requested
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Ask the server to stop
+    ///
+    /// Watched listeners are woken by connecting to them, so the accepting thread notices.
+    pub fn request(&self) {
+        if self.requested.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        for waker in self.listeners.lock().unwrap().iter() {
+            match waker {
+                Waker::Tcp(addr) => { let _ = TcpStream::connect_timeout(addr, Duration::from_secs(1)); },
+                #[cfg(unix)]
+                Waker::Unix(path) => { let _ = std::os::unix::net::UnixStream::connect(path); },
+            }
+        }
+    }
+
+    /// Wake the thread accepting on `listener` when a shutdown is requested
+    ///
+    /// Unix sockets without a path (unnamed or abstract) cannot be woken, so the thread only
+    /// notices with the next connection.
+    pub fn watch(&self, listener: &Listener) {
+        let waker = if let Some(mut addr) = listener.tcp_addr() {
+            // A listener on every interface can be reached on the loopback interface
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr.ip() {
+                    IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                });
+            }
+            Waker::Tcp(addr)
+        } else {
+            #[cfg(unix)]
+            match listener.unix_path() {
+                Some(path) => Waker::Unix(path),
+                None => return,
+            }
+            #[cfg(not(unix))]
+            return;
+        };
+        self.listeners.lock().unwrap().push(waker);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_wakes_accept() {
+        let listener = Listener::bind_tcp(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+        let shutdown = Shutdown::new();
+        shutdown.watch(&listener);
+        let handle = shutdown.clone();
+        let acceptor = std::thread::spawn(move || {
+            while listener.accept().is_ok() {
+                if handle.is_requested() {
+                    return true;
+                }
+            }
+            false
+        });
+        shutdown.request();
+        assert!(acceptor.join().unwrap());
+    }
+}
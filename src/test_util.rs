@@ -0,0 +1,10 @@
+//! Helpers shared by the tests
+
+use std::{fs, path::PathBuf};
+
+/// Create an empty temporary directory, removed with its contents when the guard drops
+pub fn sandbox(name: &str) -> scopeguard::ScopeGuard<PathBuf, impl FnOnce(PathBuf)> {
+    let dir = std::env::temp_dir().join(format!("smd-{name}-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    scopeguard::guard(dir, |dir| { fs::remove_dir_all(dir).ok(); })
+}
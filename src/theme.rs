@@ -0,0 +1,201 @@
+//! Themes: the templates and static files that pages are rendered with
+//!
+//! Both are looked up in layers (see `Config::static_dirs` and `Config::template_dirs`), so a
+//! single file of a theme can be replaced without copying the rest. A template can build on the
+//! version it replaces by naming it with a `parent/` prefix, as in
+//!
+//! ```text
+//! {% extends "parent/base.html" %}
+//! {% block title %}My notes{% endblock title %}
+//! ```
+//!
+//! The files of `sample/` are compiled into the binary as the lowest layer, so the server works
+//! without any theme directories. `export` writes them out as a starting point for a theme.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use tera::Tera;
+use walkdir::WalkDir;
+
+/// Prefix naming the version of a template in the next layer down
+pub const PARENT_PREFIX: &str = "parent/";
+
+/// The built-in templates, by name
+pub const TEMPLATES: &[(&str, &str)] = &[
+    ("base.html", include_str!("../sample/templates/base.html")),
+    ("directory-chunk.html", include_str!("../sample/templates/directory-chunk.html")),
+    ("directory.html", include_str!("../sample/templates/directory.html")),
+    ("macros.html", include_str!("../sample/templates/macros.html")),
+    ("markdown.html", include_str!("../sample/templates/markdown.html")),
+];
+
+/// The built-in static files, by path
+pub const STATIC_FILES: &[(&str, &[u8])] = &[
+    ("aux.css", include_bytes!("../sample/static/aux.css")),
+    ("main.js", include_bytes!("../sample/static/main.js")),
+    ("styles.css", include_bytes!("../sample/static/styles.css")),
+];
+
+/// A built-in static file
+pub fn static_file(path: &Path) -> Option<(&'static str, &'static [u8])> {
+    STATIC_FILES.iter().copied().find(|(name, _)| path == Path::new(name))
+}
+
+/// Only the built-in templates, for when the others fail to load
+pub fn builtin_templates() -> Tera {
+    let mut tera = Tera::default();
+    tera.add_raw_templates(TEMPLATES.to_vec()).expect("built-in templates are valid");
+    tera
+}
+
+/// Write the built-in theme to `dir`, as `static/` and `templates/`
+///
+/// Existing files are never overwritten.
+pub fn export(dir: &Path) -> io::Result<()> {
+    let files = STATIC_FILES.iter().map(|(name, content)| (Path::new("static").join(name), *content))
+        .chain(TEMPLATES.iter().map(|(name, content)| (Path::new("templates").join(name), content.as_bytes())));
+    for (path, content) in files {
+        let path = dir.join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::OpenOptions::new().write(true).create_new(true).open(&path)
+            .and_then(|mut file| file.write_all(content))
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+    }
+    Ok(())
+}
+
+/// Load the templates of every layer, the first layer with a template winning
+///
+/// The built-in templates are the last layer. Every version is also registered as `@N/NAME`
+/// for layer `N`, which is what `parent/NAME` references are rewritten to. Missing directories
+/// are skipped.
+pub fn load_templates<'a, I>(dirs: I) -> tera::Result<Tera>
+        where I: IntoIterator<Item = &'a PathBuf> {
+    let mut layers: Vec<Vec<(String, String)>> = dirs.into_iter()
+        .map(|dir| read_templates(dir))
+        .collect::<Result<_, _>>()?;
+    layers.push(TEMPLATES.iter().map(|(name, content)| (name.to_string(), content.to_string())).collect());
+    let provides = |layer: usize, name: &str| layers[layer].iter().any(|(n, _)| n == name);
+    let mut templates: Vec<(String, String)> = Vec::new();
+    for (layer, contents) in layers.iter().enumerate() {
+        for (name, content) in contents {
+            let content = resolve_parents(content, |parent| {
+                (layer + 1..layers.len()).find(|&lower| provides(lower, parent))
+            });
+            if !(0..layer).any(|upper| provides(upper, name)) {
+                templates.push((name.clone(), content.clone()));
+            }
+            templates.push((format!("@{layer}/{name}"), content));
+        }
+    }
+    let mut tera = Tera::default();
+    tera.add_raw_templates(templates)?;
+    Ok(tera)
+}
+
+/// The html templates under `dir`, named by their path relative to it
+fn read_templates(dir: &Path) -> tera::Result<Vec<(String, String)>> {
+    let mut templates = Vec::new();
+    if !dir.is_dir() {
+        return Ok(templates);
+    }
+    for entry in WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry.map_err(|e| tera::Error::msg(e.to_string()))?;
+        let path = entry.path();
+        if !entry.file_type().is_file() || path.extension().is_none_or(|ext| ext != "html") {
+            continue;
+        }
+        let name = path.strip_prefix(dir)
+            .map_err(|e| tera::Error::msg(e.to_string()))?
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let content = fs::read_to_string(path)
+            .map_err(|e| tera::Error::chain(format!("{}", path.display()), e))?;
+        templates.push((name, content));
+    }
+    Ok(templates)
+}
+
+/// Rewrite quoted `parent/NAME` references to the layer `find` returns for `NAME`
+///
+/// References without a lower layer are left alone, so Tera reports them as missing.
+fn resolve_parents(content: &str, find: impl Fn(&str) -> Option<usize>) -> String {
+    let mut out = String::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(start) = rest.find(PARENT_PREFIX) {
+        let quote = rest[..start].chars().next_back();
+        let (before, after) = rest.split_at(start);
+        out.push_str(before);
+        let name = &after[PARENT_PREFIX.len()..];
+        let end = match quote {
+            Some(q @ ('"' | '\'')) => name.find(q),
+            _ => None,
+        };
+        match end.and_then(|end| Some((end, find(&name[..end])?))) {
+            Some((end, layer)) => {
+                out.push_str(&format!("@{layer}/{}", &name[..end]));
+                rest = &name[end..];
+            },
+            None => {
+                out.push_str(PARENT_PREFIX);
+                rest = name;
+            },
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::test_util;
+    use super::*;
+
+    #[test]
+    fn templates_extend_lower_layers() {
+        let base = test_util::sandbox("theme");
+        let layer = |name: &str, files: &[(&str, &str)]| {
+            let dir = base.join(name);
+            fs::create_dir_all(&dir).unwrap();
+            for (file, content) in files {
+                fs::write(dir.join(file), content).unwrap();
+            }
+            dir
+        };
+        let dirs = [
+            layer("user", &[("base.html", r#"{% extends "parent/base.html" %}{% block b %}user {{ super() }}{% endblock b %}"#)]),
+            layer("theme", &[("base.html", r#"{% extends 'parent/base.html' %}{% block b %}theme {{ super() }}{% endblock b %}"#)]),
+            layer("defaults", &[
+                ("base.html", "{% block b %}default{% endblock b %}"),
+                ("page.html", r#"{% extends "base.html" %}"#),
+            ]),
+            base.join("missing"),
+        ];
+        let tera = load_templates(dirs.iter()).unwrap();
+        let context = tera::Context::new();
+        assert_eq!(tera.render("base.html", &context).unwrap(), "user theme default");
+        // Templates of lower layers use the overridden versions of the others
+        assert_eq!(tera.render("page.html", &context).unwrap(), "user theme default");
+        // The built-in templates fill in the rest
+        assert!(tera.get_template_names().any(|name| name == "markdown.html"));
+    }
+
+    #[test]
+    fn exports_builtin_theme() {
+        let dir = test_util::sandbox("export");
+        export(&dir).unwrap();
+        assert_eq!(fs::read(dir.join("static/main.js")).unwrap(), static_file(Path::new("main.js")).unwrap().1);
+        assert!(dir.join("templates/base.html").is_file());
+        // Customized files are not overwritten
+        assert!(export(&dir).is_err());
+    }
+}
@@ -0,0 +1,132 @@
+//! TLS termination
+//!
+//! Certificates and keys are read from PEM files. For serving on a LAN without a real
+//! certificate, a self-signed one can be generated on the first start and reused afterwards, so
+//! a browser only has to be told to trust it once.
+
+use std::{
+    fs,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use rustls::ServerConfig;
+
+use crate::config::{self, Config};
+
+/// Build the TLS settings for the server, or `None` if TLS is not enabled
+///
+/// With `tls_self_signed`, a certificate is generated if the files do not exist yet. Without
+/// paths, it is kept next to the user's config file.
+pub fn server_config(config: &Config) -> io::Result<Option<Arc<ServerConfig>>> {
+    if !config.tls_enabled() {
+        return Ok(None);
+    }
+    let (cert, key) = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => (cert.clone(), key.clone()),
+        (None, None) if config.tls_self_signed => default_paths()?,
+        _ => return Err(invalid("both a TLS certificate and key are needed")),
+    };
+    if config.tls_self_signed && !cert.exists() && !key.exists() {
+        generate_self_signed(&cert, &key, &hostnames(config))?;
+        eprintln!("Generated a self-signed certificate in {}", cert.display());
+    }
+    load(&cert, &key).map(|c| Some(Arc::new(c)))
+}
+
+/// Read a certificate chain and private key from PEM files
+pub fn load(cert: &Path, key: &Path) -> io::Result<ServerConfig> {
+    let open = |path: &Path| fs::File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())));
+    let certs = rustls_pemfile::certs(&mut open(cert)?).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(invalid(&format!("no certificates in {}", cert.display())));
+    }
+    let key = rustls_pemfile::private_key(&mut open(key)?)?
+        .ok_or_else(|| invalid(&format!("no private key in {}", key.display())))?;
+    let mut tls = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| invalid(&e.to_string()))?;
+    tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(tls)
+}
+
+/// Write a new self-signed certificate and its key, valid for `hostnames`
+pub fn generate_self_signed(cert: &Path, key: &Path, hostnames: &[String]) -> io::Result<()> {
+    let generated = rcgen::generate_simple_self_signed(hostnames.to_vec())
+        .map_err(|e| invalid(&e.to_string()))?;
+    for path in [cert, key] {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+    }
+    fs::write(cert, generated.cert.pem())?;
+    write_private(key, generated.key_pair.serialize_pem().as_bytes())
+}
+
+/// Names the generated certificate is valid for: the loopback names, the configured address,
+/// and this machine's host name
+fn hostnames(config: &Config) -> Vec<String> {
+    let mut names = vec![String::from("localhost"), String::from("127.0.0.1"), String::from("::1")];
+    if !config.addr.ip().is_unspecified() {
+        names.push(config.addr.ip().to_string());
+    }
+    let host = std::env::var("HOSTNAME").ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty());
+    if let Some(host) = host {
+        names.push(format!("{host}.local"));
+        names.push(host);
+    }
+    names.dedup();
+    names
+}
+
+/// Where a self-signed certificate is kept when no paths are configured
+fn default_paths() -> io::Result<(PathBuf, PathBuf)> {
+    let dir = config::user_config_path()
+        .and_then(|path| path.parent().map(Path::to_path_buf))
+        .ok_or_else(|| invalid("no config directory for the self-signed certificate"))?;
+    Ok((dir.join("cert.pem"), dir.join("key.pem")))
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+    fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600)
+        .open(path)?
+        .write_all(contents)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    fs::write(path, contents)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_certificate_loads() {
+        let dir = crate::test_util::sandbox("tls");
+        let config = Config::build()
+            .set_self_signed(true)
+            .set_tls_cert(dir.join("cert.pem").to_str().unwrap())
+            .set_tls_key(dir.join("key.pem").to_str().unwrap())
+            .build();
+        assert!(server_config(&config).unwrap().is_some());
+        // The second start reuses the certificate
+        let cert = fs::read(dir.join("cert.pem")).unwrap();
+        assert!(server_config(&config).unwrap().is_some());
+        assert_eq!(cert, fs::read(dir.join("cert.pem")).unwrap());
+    }
+}
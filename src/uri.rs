@@ -4,8 +4,22 @@
 //! returned that don't perfectly match those in webroot.
 //!
 //! - Priority is to match existing files under $WEB_ROOT/
+//! - A directory with one of the `index_files` in it resolves to that document as well
 //! - If the file does not exist, check to see if one exists with ".md"
-//! - Finally, look in $STATIC_DIR/ for the file
+//! - Then, look in $STATIC_DIR/ (and any static overrides before it) for the file
+//! - Then, fall back on the static files built into the binary
+//! - Finally, look for notes with a similar name anywhere, so links keep working when notes are
+//!   moved ("smart links"). The names are found ahead of time, and again on `refresh_names`.
+//!
+//! When the site is served under a base path (like `/notes/`), it is stripped first, and
+//! anything outside of it is not found. Mounts are matched next, by their longest prefix, so
+//! `/work/todo` is looked up in the directory mounted at `/work` (without the static fallback).
+//!
+//! The web root's `smd.toml` is never served, even though it is in the vault.
+//!
+//! Request paths are normalized before they are joined onto a root, and anything that would
+//! escape the root (through `..` segments, encoded separators, or symlinks, depending on the
+//! `SymlinkPolicy`) is `Resolved::Forbidden`.
 //!
 //! # Possible improvements
 //!
@@ -13,19 +27,50 @@
 //! - A resolver struct for passing around the config
 
 use std::{
-    path::PathBuf, 
+    collections::HashMap,
+    path::{Component, Path, PathBuf}, 
     ffi::{OsStr, OsString},
+    sync::RwLock,
 };
 
-use url_escape::decode as decode_url;
+use url_escape::{decode as decode_url, encode_component as encode_url};
+use walkdir::WalkDir;
 
 
-use crate::config::Config;
+use crate::{
+    config::{Config, SymlinkPolicy, CONFIG_FILE_NAME},
+    theme,
+};
 
 
 pub struct Resolver {
-    rootdir: PathBuf,
-    staticdir: PathBuf,
+    /// The mounts, longest prefix first, and then the web root
+    roots: Vec<Root>,
+    /// Static directories in search order, with their canonical paths
+    static_dirs: Vec<(PathBuf, PathBuf)>,
+    symlinks: SymlinkPolicy,
+    base_path: String,
+    index_files: Vec<String>,
+    /// The notes in every root, by the `link_key` of their file name
+    names: RwLock<HashMap<String, Vec<NoteName>>>,
+}
+
+/// A note, as smart links and wiki-links find it
+struct NoteName {
+    link: String,
+    path: PathBuf,
+    /// The `link_key` of every segment of its uri path (without the base path), joined by `/`
+    key: String,
+}
+
+/// A directory served under a URL prefix
+struct Root {
+    /// Empty for the web root
+    prefix: String,
+    dir: PathBuf,
+    // Canonical root, for checking that resolved files stay inside it
+    canonical: PathBuf,
+    read_only: bool,
 }
 
 #[derive(Debug)]
@@ -33,21 +78,95 @@ pub enum Resolved {
     File(PathBuf),
     Markdown(PathBuf),
     Directory(PathBuf),
+    /// Links to notes with a similar name, when nothing is at the path itself
+    Closest(Vec<String>),
+    /// A directory with an index document, which is rendered as its page
+    Index { dir: PathBuf, document: PathBuf },
+    /// A static file built into the binary, by name (see `theme::STATIC_FILES`)
+    Builtin(&'static str),
+    /// A directory (or the base path) without its trailing slash, which should be redirected to
+    Redirect(String),
+    Forbidden,
     None,
 }
 
 impl Resolver {
     pub fn new(config: &Config) -> Resolver {
-        Resolver { staticdir: config.staticdir.clone(), rootdir: config.rootdir.clone() }
+        let mut roots: Vec<Root> = config.mounts.iter()
+            .map(|m| Root {
+                prefix: m.prefix.clone(),
+                dir: m.root.clone(),
+                canonical: canonical(&m.root),
+                read_only: m.read_only,
+            })
+            .collect();
+        roots.sort_by_key(|root| std::cmp::Reverse(root.prefix.len()));
+        roots.push(Root {
+            prefix: String::new(),
+            dir: config.rootdir.clone(),
+            canonical: canonical(&config.rootdir),
+            read_only: false,
+        });
+        let resolver = Resolver { 
+            roots,
+            static_dirs: config.static_dirs().map(|dir| (dir.clone(), canonical(dir))).collect(),
+            symlinks: config.symlinks,
+            base_path: config.base_path.clone(),
+            index_files: config.index_files.clone(),
+            names: RwLock::new(HashMap::new()),
+        };
+        resolver.refresh_names();
+        resolver
+    }
+
+    /// Find the notes again, for smart links to ones that were added, moved or removed
+    pub fn refresh_names(&self) {
+        let mut names: HashMap<String, Vec<NoteName>> = HashMap::new();
+        for (root, relpath, path) in self.notes() {
+            let segments: Vec<String> = root.prefix.split('/')
+                .map(String::from)
+                .chain(relpath.components().map(|c| c.as_os_str().to_string_lossy().to_string()))
+                .collect();
+            let note = NoteName { link: self.link(root, &relpath), path, key: wiki_key(&segments.join("/")) };
+            let name = link_key(segments.last().map_or("", String::as_str));
+            names.entry(name).or_default().push(note);
+        }
+        *self.names.write().unwrap() = names;
     }
 
+    #[allow(clippy::needless_return)]
     pub fn lookup(&self, uri: &http::Uri) -> Resolved {
         let mdext: &OsStr = OsStr::new("md");
-        let relpath = force_relative(&decode_url(uri.path()));
-        // Check under webroot
-        let mut path = self.rootdir.join(&relpath);
+        let (root, uri_path) = match self.find_root(uri.path()) {
+            Some((root, Some(rest))) => (root, rest),
+            Some((root, None)) => return Resolved::Redirect(format!("{}{}/", self.base_path, root.prefix)),
+            None => return Resolved::None,
+        };
+        let relpath = match normalize(uri_path) {
+            Some(relpath) => relpath,
+            None => return Resolved::Forbidden,
+        };
+        // The web root's config is not content
+        if root.prefix.is_empty() && relpath == Path::new(CONFIG_FILE_NAME) {
+            return Resolved::Forbidden;
+        }
+        // Check under the root
+        let mut path = root.dir.join(&relpath);
+        if (path.is_dir() || path.is_file()) && !self.is_confined(&path, &relpath, &root.canonical) {
+            return Resolved::Forbidden;
+        }
         if path.is_dir() {
-            return Resolved::Directory(path);
+            let index = self.index_files.iter().find_map(|name| {
+                let document = path.join(name);
+                let confined = document.is_file() && self.is_confined(&document, &relpath.join(name), &root.canonical);
+                confined.then_some(document)
+            });
+            return match index {
+                // Relative links in the document are relative to the directory
+                Some(_) if !uri_path.ends_with('/') => Resolved::Redirect(format!("{}/", self.link(root, &relpath))),
+                Some(document) => Resolved::Index { dir: path, document },
+                None => Resolved::Directory(path),
+            };
         } else if path.is_file() {
             return if path.extension() == Some(mdext) { 
                 Resolved::Markdown(path)
@@ -64,22 +183,412 @@ impl Resolver {
             tmp.push(mdext);
             path.set_file_name(tmp);
             if path.is_file() {
+                let relpath = relpath.with_file_name(path.file_name().unwrap());
+                if !self.is_confined(&path, &relpath, &root.canonical) {
+                    return Resolved::Forbidden;
+                }
                 return Resolved::Markdown(path);
             }
         }
-        // Look in staticdir
-        let path = self.staticdir.join(&relpath);
-        if path.is_file() {
-            return Resolved::File(path);
+        if root.prefix.is_empty() {
+            // Look in the static directories, the first one with the file winning
+            for (dir, canonical_dir) in self.static_dirs.iter() {
+                let path = dir.join(&relpath);
+                if path.is_file() {
+                    if !self.is_confined(&path, &relpath, canonical_dir) {
+                        return Resolved::Forbidden;
+                    }
+                    return Resolved::File(path);
+                }
+            }
+            if let Some((name, _)) = theme::static_file(&relpath) {
+                return Resolved::Builtin(name);
+            }
+        }
+        let closest = self.closest_matches(&relpath);
+        if !closest.is_empty() {
+            return Resolved::Closest(closest);
         }
         // Finally, nothing is found
         return Resolved::None;
     }
 
+    /// Links to notes in any root with the same name as `relpath`, loosely compared
+    ///
+    /// Names match ignoring case, a `.md` extension, and whether words are separated by spaces,
+    /// dashes or underscores. The notes are the ones found by the last `refresh_names`.
+    fn closest_matches(&self, relpath: &Path) -> Vec<String> {
+        let key = match relpath.file_name().and_then(OsStr::to_str) {
+            Some(name) => link_key(name),
+            None => return Vec::new(),
+        };
+        match self.names.read().unwrap().get(&key) {
+            Some(notes) => notes.iter().map(|note| note.link.clone()).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// The uri path of a file, relative to its root
+    fn link(&self, root: &Root, relpath: &Path) -> String {
+        let mut link = format!("{}{}", self.base_path, root.prefix);
+        for segment in relpath.components() {
+            link.push('/');
+            link.push_str(&encode_url(&segment.as_os_str().to_string_lossy()));
+        }
+        link
+    }
+
+    /// Resolve the target of a wiki-link, like `Note` or `folder/Note`
+    ///
+    /// Notes are found by name, like smart links, preferring the ones in the target's folder.
+    /// Anything else (like an image or a directory) has to be at the path from the root. Returns
+    /// the link to the target, or the link it would have if it does not exist. A target matching
+    /// several notes links to their disambiguation page.
+    pub fn wiki_link(&self, target: &str) -> Result<String, String> {
+        let segments: Vec<String> = target.split('/')
+            .filter(|s| !s.is_empty())
+            .map(|s| encode_url(s).into_owned())
+            .collect();
+        let link = format!("{}/{}", self.base_path, segments.join("/"));
+        match self.find_notes(&wiki_key(target)).as_slice() {
+            [(note, _)] => return Ok(note.clone()),
+            [] => (),
+            _ => return Ok(link),
+        }
+        let uri = match link.parse::<http::Uri>() {
+            Ok(uri) => uri,
+            Err(_) => return Err(link),
+        };
+        match self.lookup(&uri) {
+            Resolved::File(_) | Resolved::Markdown(_) | Resolved::Directory(_) | Resolved::Index { .. } => Ok(link),
+            Resolved::Redirect(location) => Ok(location),
+            _ => Err(link),
+        }
+    }
+
+    /// The note a link within the site points to, found like a wiki-link
+    ///
+    /// `None` if there is no such note, or several of them.
+    pub fn note(&self, link: &str) -> Option<PathBuf> {
+        let (root, rest) = self.find_root(link)?;
+        let relpath = normalize(rest?)?;
+        let segments: Vec<_> = relpath.iter().map(OsStr::to_string_lossy).collect();
+        let mut notes = self.find_notes(&wiki_key(&format!("{}/{}", root.prefix, segments.join("/"))));
+        match notes.len() {
+            1 => notes.pop().map(|(_, path)| path),
+            _ => None,
+        }
+    }
+
+    /// Links to the notes a `wiki_key` could mean, with their paths
+    ///
+    /// A note at that path from the root wins, then the ones in a folder with that path, and
+    /// then the ones with the same name anywhere.
+    fn find_notes(&self, key: &str) -> Vec<(String, PathBuf)> {
+        let names = self.names.read().unwrap();
+        let name = key.rsplit('/').next().unwrap_or_default();
+        let notes: Vec<&NoteName> = names.get(name).map(|notes| notes.iter().collect()).unwrap_or_default();
+        let in_folder: Vec<&NoteName> = notes.iter().copied()
+            .filter(|note| note.key == key || note.key.ends_with(&format!("/{key}")))
+            .collect();
+        let found = match in_folder.iter().find(|note| note.key == key) {
+            Some(exact) => vec![*exact],
+            None if !in_folder.is_empty() => in_folder,
+            None => notes,
+        };
+        found.into_iter().map(|note| (note.link.clone(), note.path.clone())).collect()
+    }
+
+    /// Whether the uri is in a read-only mount
+    pub fn is_read_only(&self, uri: &http::Uri) -> bool {
+        self.find_root(uri.path()).is_some_and(|(root, _)| root.read_only)
+    }
+
+    /// The root a uri path is under, and the rest of the path after its prefix
+    ///
+    /// The rest is `None` for the prefix without a trailing slash, which should be redirected.
+    /// Returns `None` outside of the base path.
+    fn find_root<'a>(&self, uri_path: &'a str) -> Option<(&Root, Option<&'a str>)> {
+        let uri_path = match uri_path.strip_prefix(&self.base_path)? {
+            "" => "",
+            rest if rest.starts_with('/') => rest,
+            _ => return None,
+        };
+        self.roots.iter().find_map(|root| match uri_path.strip_prefix(&root.prefix)? {
+            "" => Some((root, None)),
+            rest if rest.starts_with('/') => Some((root, Some(rest))),
+            _ => None,
+        })
+    }
+
+    /// The markdown documents in every root, skipping hidden files and directories
+    pub fn documents(&self) -> Vec<PathBuf> {
+        self.notes().into_iter().map(|(_, _, path)| path).collect()
+    }
+
+    /// The directories that may have markdown documents in them, including the roots
+    pub fn document_dirs(&self) -> Vec<PathBuf> {
+        self.roots.iter()
+            .flat_map(walk)
+            .filter(|e| e.file_type().is_dir())
+            .map(walkdir::DirEntry::into_path)
+            .collect()
+    }
+
+    /// The markdown documents in every root, with the root and their path relative to it
+    fn notes(&self) -> Vec<(&Root, PathBuf, PathBuf)> {
+        let mut notes = Vec::new();
+        for root in self.roots.iter() {
+            let files = walk(root)
+                .filter(|e| e.file_type().is_file() && e.path().extension() == Some(OsStr::new("md")));
+            for file in files {
+                if let Ok(relpath) = file.path().strip_prefix(&root.dir) {
+                    if self.is_confined(file.path(), relpath, &root.canonical) {
+                        notes.push((root, relpath.to_path_buf(), file.path().to_path_buf()));
+                    }
+                }
+            }
+        }
+        notes
+    }
+
+    /// The uri path of a file under one of the roots
+    pub fn link_to(&self, path: &Path) -> Option<String> {
+        self.roots.iter().find_map(|root| Some(self.link(root, path.strip_prefix(&root.dir).ok()?)))
+    }
+
+    /// Whether an existing path is allowed by the symlink policy
+    ///
+    /// `relpath` is the normalized path relative to the (canonical) `root`.
+    fn is_confined(&self, path: &Path, relpath: &Path, root: &Path) -> bool {
+        let resolved = match path.canonicalize() {
+            Ok(resolved) => resolved,
+            Err(_) => return false,
+        };
+        match self.symlinks {
+            SymlinkPolicy::Follow => true,
+            SymlinkPolicy::WithinRoot => resolved.starts_with(root),
+            // Without symlinks, the canonical path is just the joined one
+            SymlinkPolicy::Never => resolved == root.join(relpath),
+        }
+    }
+
+}
+
+/// Normalize the (still percent-encoded) path of a uri into a path relative to the root
+///
+/// Empty and `.` segments are dropped, and `..` removes the previous segment. Returns `None` if
+/// the path would escape the root, or if a decoded segment contains a separator or NUL byte,
+/// since those can only come from percent-encoding.
+pub fn normalize(uri_path: &str) -> Option<PathBuf> {
+    let mut segments: Vec<String> = Vec::new();
+    for segment in uri_path.split('/') {
+        let segment = decode_url(segment);
+        if segment.contains(['/', '\\', '\0']) {
+            return None;
+        }
+        match segment.as_ref() {
+            "" | "." => continue,
+            ".." => { segments.pop()?; },
+            s => segments.push(s.to_string()),
+        }
+    }
+    let path: PathBuf = segments.iter().collect();
+    // Anything else special to the platform (like a windows drive prefix) is rejected
+    if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return None;
+    }
+    Some(path)
 }
 
-fn force_relative(uri: &str) -> PathBuf {
-    assert!(uri.starts_with('/'), 
-            "The uri path for a request should always be absolute");
-    PathBuf::from(&uri[1..])
+/// A file name in the form that smart links compare: lowercase, without `.md`, and with `-` for
+/// spaces and underscores
+fn link_key(name: &str) -> String {
+    let name = name.to_lowercase();
+    let name = name.strip_suffix(".md").unwrap_or(&name);
+    name.chars().map(|c| if c == ' ' || c == '_' { '-' } else { c }).collect()
+}
+
+/// A wiki-link target (or uri path) in the form that wiki-links compare: the `link_key` of every
+/// segment, joined by `/`
+fn wiki_key(target: &str) -> String {
+    let segments: Vec<String> = target.split('/')
+        .filter(|s| !s.is_empty())
+        .map(link_key)
+        .collect();
+    segments.join("/")
+}
+
+/// Everything under a root that may be a note, which leaves out hidden files and directories, and
+/// build directories (marked with a `CACHEDIR.TAG`, like cargo's `target`)
+fn walk(root: &Root) -> impl Iterator<Item = walkdir::DirEntry> {
+    WalkDir::new(&root.dir).sort_by_file_name().into_iter()
+        .filter_entry(|e| e.depth() == 0 || !is_hidden(e.file_name()) && !is_cache_dir(e))
+        .filter_map(Result::ok)
+}
+
+/// Whether a file name is hidden, by starting with a dot
+pub fn is_hidden(name: &OsStr) -> bool {
+    name.to_string_lossy().starts_with('.')
+}
+
+fn is_cache_dir(entry: &walkdir::DirEntry) -> bool {
+    entry.file_type().is_dir() && entry.path().join("CACHEDIR.TAG").is_file()
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::test_util;
+    use super::*;
+
+    /// Create a web root with a secret file next to it, removed when the guard drops
+    fn sandbox(name: &str) -> scopeguard::ScopeGuard<PathBuf, impl FnOnce(PathBuf)> {
+        let base = test_util::sandbox(&format!("uri-{name}"));
+        fs::create_dir_all(base.join("root/notes")).unwrap();
+        fs::write(base.join("root/notes/today.md"), "# Today").unwrap();
+        fs::write(base.join("secret.txt"), "secret").unwrap();
+        base
+    }
+
+    fn resolver(base: &Path, policy: SymlinkPolicy) -> Resolver {
+        let config = Config::build()
+            .set_root(base.join("root").to_str().unwrap())
+            .set_static(base.join("static").to_str().unwrap())
+            .set_symlink_policy(policy)
+            .build();
+        Resolver::new(&config)
+    }
+
+    fn lookup(resolver: &Resolver, uri: &str) -> Resolved {
+        resolver.lookup(&uri.parse::<http::Uri>().unwrap())
+    }
+
+    #[test]
+    fn normalizes_dot_segments() {
+        assert_eq!(normalize("/a/./b/../c"), Some(PathBuf::from("a/c")));
+        assert_eq!(normalize("//a///b/"), Some(PathBuf::from("a/b")));
+        assert_eq!(normalize("/My%20Notes/x.md"), Some(PathBuf::from("My Notes/x.md")));
+        assert_eq!(normalize("/"), Some(PathBuf::new()));
+    }
+
+    #[test]
+    fn rejects_escaping_paths() {
+        assert_eq!(normalize("/../secret.txt"), None);
+        assert_eq!(normalize("/notes/../../secret.txt"), None);
+        assert_eq!(normalize("/%2e%2e/secret.txt"), None);
+        assert_eq!(normalize("/notes/..%2f..%2fsecret.txt"), None);
+        assert_eq!(normalize("/notes%5c..%5c..%5csecret.txt"), None);
+        assert_eq!(normalize("/today.md%00.png"), None);
+    }
+
+    #[test]
+    fn resolves_inside_root() {
+        let base = sandbox("inside");
+        let resolver = resolver(&base, SymlinkPolicy::WithinRoot);
+        assert!(matches!(lookup(&resolver, "/notes/today"), Resolved::Markdown(_)));
+        assert!(matches!(lookup(&resolver, "/notes/./../notes/"), Resolved::Directory(_)));
+        fs::write(base.join("root/notes/README.md"), "# Notes").unwrap();
+        assert!(matches!(lookup(&resolver, "/notes/"), Resolved::Index { document, .. } if document.ends_with("notes/README.md")));
+        assert!(matches!(lookup(&resolver, "//notes"), Resolved::Redirect(location) if location == "/notes/"));
+        assert!(matches!(lookup(&resolver, "/%2e%2e/secret.txt"), Resolved::Forbidden));
+        assert!(matches!(lookup(&resolver, "//etc/passwd"), Resolved::None));
+        assert!(matches!(lookup(&resolver, "/styles.css"), Resolved::Builtin("styles.css")));
+        fs::write(base.join("root/smd.toml"), "").unwrap();
+        assert!(matches!(lookup(&resolver, "/smd.toml"), Resolved::Forbidden));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_policy_is_enforced() {
+        let base = sandbox("symlinks");
+        std::os::unix::fs::symlink(base.join("secret.txt"), base.join("root/outside.txt")).unwrap();
+        std::os::unix::fs::symlink(base.join("root/notes/today.md"), base.join("root/inside.md")).unwrap();
+
+        let within = resolver(&base, SymlinkPolicy::WithinRoot);
+        assert!(matches!(lookup(&within, "/outside.txt"), Resolved::Forbidden));
+        assert!(matches!(lookup(&within, "/inside"), Resolved::Markdown(_)));
+
+        let never = resolver(&base, SymlinkPolicy::Never);
+        assert!(matches!(lookup(&never, "/inside.md"), Resolved::Forbidden));
+        assert!(matches!(lookup(&never, "/notes/today.md"), Resolved::Markdown(_)));
+
+        let follow = resolver(&base, SymlinkPolicy::Follow);
+        assert!(matches!(lookup(&follow, "/outside.txt"), Resolved::File(_)));
+    }
+
+    #[test]
+    fn finds_closest_matches() {
+        let base = sandbox("closest");
+        fs::create_dir_all(base.join("root/archive")).unwrap();
+        fs::write(base.join("root/archive/Meeting Notes.md"), "").unwrap();
+        let resolver = resolver(&base, SymlinkPolicy::WithinRoot);
+        assert!(matches!(lookup(&resolver, "/meeting_notes"), Resolved::Closest(links) if links == ["/archive/Meeting%20Notes.md"]));
+        assert!(matches!(lookup(&resolver, "/old/TODAY.md"), Resolved::Closest(links) if links == ["/notes/today.md"]));
+        fs::write(base.join("root/archive/today.md"), "").unwrap();
+        assert!(matches!(lookup(&resolver, "/today"), Resolved::Closest(links) if links.len() == 1));
+        resolver.refresh_names();
+        assert!(matches!(lookup(&resolver, "/today"), Resolved::Closest(links) if links.len() == 2));
+        // Only notes are found
+        fs::write(base.join("root/archive/photo.png"), "").unwrap();
+        resolver.refresh_names();
+        assert!(matches!(lookup(&resolver, "/photo.png"), Resolved::None));
+        assert!(matches!(lookup(&resolver, "/tomorrow"), Resolved::None));
+        assert_eq!(resolver.wiki_link("meeting notes"), Ok(String::from("/archive/Meeting%20Notes.md")));
+        assert_eq!(resolver.wiki_link("Tomorrow"), Err(String::from("/Tomorrow")));
+        // The folder picks between notes with the same name, and the name alone is enough
+        assert_eq!(resolver.wiki_link("archive/Today"), Ok(String::from("/archive/today.md")));
+        assert_eq!(resolver.wiki_link("Today"), Ok(String::from("/Today")));
+        assert_eq!(resolver.wiki_link("elsewhere/Meeting Notes"), Ok(String::from("/archive/Meeting%20Notes.md")));
+        assert_eq!(resolver.wiki_link("archive/photo.png"), Ok(String::from("/archive/photo.png")));
+    }
+
+    #[test]
+    fn skips_hidden_and_build_dirs() {
+        let base = sandbox("document-dirs");
+        fs::create_dir_all(base.join("root/.git/objects")).unwrap();
+        fs::create_dir_all(base.join("root/target/debug")).unwrap();
+        fs::write(base.join("root/target/CACHEDIR.TAG"), "Signature: 8a477f597d28d172789f06886806bc55").unwrap();
+        fs::write(base.join("root/target/debug/README.md"), "").unwrap();
+        let resolver = resolver(&base, SymlinkPolicy::WithinRoot);
+        assert_eq!(resolver.document_dirs(), [base.join("root"), base.join("root/notes")]);
+        assert_eq!(resolver.documents(), [base.join("root/notes/today.md")]);
+    }
+
+    #[test]
+    fn strips_base_path() {
+        let base = sandbox("base-path");
+        let config = Config::build()
+            .set_root(base.join("root").to_str().unwrap())
+            .set_base_path("/vault/")
+            .build();
+        let resolver = Resolver::new(&config);
+        assert!(matches!(lookup(&resolver, "/vault/notes/today"), Resolved::Markdown(_)));
+        assert!(matches!(lookup(&resolver, "/vault/"), Resolved::Directory(_)));
+        assert!(matches!(lookup(&resolver, "/vault"), Resolved::Redirect(to) if to == "/vault/"));
+        assert!(matches!(lookup(&resolver, "/notes/today"), Resolved::None));
+        assert!(matches!(lookup(&resolver, "/vaultnotes/today"), Resolved::None));
+    }
+
+    #[test]
+    fn dispatches_to_mounts() {
+        let base = sandbox("mounts");
+        let mut work = crate::config::Mount::new("/work", &base.join("root/notes")).unwrap();
+        work.read_only = true;
+        let config = Config::build()
+            .set_root(base.join("root").to_str().unwrap())
+            .add_mount(work)
+            .build();
+        let resolver = Resolver::new(&config);
+        assert!(matches!(lookup(&resolver, "/work/today"), Resolved::Markdown(p) if p.starts_with(base.join("root/notes"))));
+        assert!(matches!(lookup(&resolver, "/work"), Resolved::Redirect(to) if to == "/work/"));
+        assert!(matches!(lookup(&resolver, "/work/../secret.txt"), Resolved::Forbidden));
+        assert!(matches!(lookup(&resolver, "/notes/today"), Resolved::Markdown(_)));
+        assert!(resolver.is_read_only(&"/work/today".parse().unwrap()));
+        assert!(!resolver.is_read_only(&"/workshop".parse().unwrap()));
+    }
 }